@@ -0,0 +1,52 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use metrics::{
+    consumer::{LatePolicy, MetricQueue, MetricQueueConfig},
+    Sample,
+};
+
+const QUEUE_SIZE: usize = 1 << 20;
+
+fn queue(sorted: bool) -> MetricQueue {
+    let mut queue = MetricQueue::with_config(MetricQueueConfig {
+        late_policy: LatePolicy::Append,
+        ..Default::default()
+    });
+    for i in 0..QUEUE_SIZE as u64 {
+        queue
+            .push(
+                Sample {
+                    time: i,
+                    value: i as f64,
+                },
+                QUEUE_SIZE,
+            )
+            .unwrap();
+    }
+    if !sorted {
+        // An out-of-order sample at the back keeps the queue unsorted
+        queue
+            .push(Sample { time: 0, value: 0. }, QUEUE_SIZE)
+            .unwrap();
+    }
+    queue
+}
+
+fn span(c: &mut Criterion) {
+    let mut group = c.benchmark_group("span_1m");
+    for sorted in [true, false] {
+        let queue = queue(sorted);
+        let name = if sorted { "binary" } else { "linear" };
+        let mid = QUEUE_SIZE as u64 / 2;
+        group.bench_with_input(BenchmarkId::new(name, "middle"), &queue, |b, queue| {
+            b.iter(|| queue.span(black_box(mid..mid + 1024)))
+        });
+        group.bench_with_input(BenchmarkId::new(name, "tail"), &queue, |b, queue| {
+            let start = QUEUE_SIZE as u64 - 1024;
+            b.iter(|| queue.span(black_box(start..)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, span);
+criterion_main!(benches);
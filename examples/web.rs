@@ -6,8 +6,17 @@ use std::{
 
 use hyped::*;
 use metrics::{
-    buf::MetricBufReaders, consumer::MetricConsumer, exporter::InProcessExporter,
-    view::scatter_chart_html, Sample, Time,
+    buf::MetricBufReaders,
+    clock::{Clock, SystemClock},
+    consumer::{KeyPattern, MetricConsumer, MetricQueueConfig},
+    exporter::InProcessExporter,
+    rollup::RollupTier,
+    shared::SharedMetricConsumer,
+    storage::{DiskStore, DiskStoreConfig},
+    synthesis::Combine,
+    view::{scatter_chart_html, MetricSyntheses},
+    wal::{FsyncPolicy, WriteAheadLog},
+    Sample, Time, TimeUnit,
 };
 use poem::{
     get, handler,
@@ -18,13 +27,15 @@ use poem::{
 use primitive::ops::{
     diff::{Diff, DiffExt},
     range::RangeAny,
-    wrap::{Map, TransposeOption, TransposeResult},
+    wrap::{Map, TransposeOption},
 };
 use serde::Deserialize;
 
+const TIME_UNIT: TimeUnit = TimeUnit::Millis;
+
 #[tokio::main]
 async fn main() {
-    let mut metric_buf_readers = MetricBufReaders::new();
+    let mut metric_buf_readers = MetricBufReaders::with_time_unit(TIME_UNIT);
     let cpu_metrics = metric_buf_readers.new_metrics("cpu".into());
     let mem_metrics = metric_buf_readers.new_metrics("mem".into());
     let swap_metrics = metric_buf_readers.new_metrics("swap".into());
@@ -36,12 +47,11 @@ async fn main() {
     let swap_total_metrics = metric_buf_readers.new_metrics("swap.total".into());
     let swap_free_metrics = metric_buf_readers.new_metrics("swap.free".into());
     std::thread::spawn(move || {
-        let now = || system_time_timestamp(SystemTime::now());
         let mut sys = sysinfo::System::new_all();
         loop {
             std::thread::sleep(Duration::from_secs(1));
             sys.refresh_all();
-            let now = now();
+            let now = SystemClock.timestamp(TIME_UNIT);
             cpu_metrics.try_push(Sample {
                 time: now,
                 value: sys.global_cpu_usage() as f64 / 100.,
@@ -86,7 +96,33 @@ async fn main() {
     });
     let mut exporter = InProcessExporter::new(metric_buf_readers);
 
-    let mut consumer = MetricConsumer::new(1024);
+    let mut consumer = MetricConsumer::with_time_unit(1024, TIME_UNIT);
+    let minute = TIME_UNIT.from_duration(Duration::from_secs(60));
+    consumer.set_default_queue_config(MetricQueueConfig {
+        max_age: Some(Duration::from_secs(60 * 60 * 24)),
+        rollups: vec![
+            RollupTier {
+                step: minute,
+                capacity: 60 * 24 * 7,
+            },
+            RollupTier {
+                step: minute * 60,
+                capacity: 24 * 365,
+            },
+        ],
+        ..Default::default()
+    });
+    // Its samples are timed around the Unix epoch
+    consumer.set_queue_config(KeyPattern::Exact("a".into()), MetricQueueConfig::default());
+    let storage = DiskStore::open("metrics-data", TIME_UNIT, DiskStoreConfig::default()).unwrap();
+    consumer.set_storage(Some(storage));
+    consumer.load_storage().unwrap();
+    // Replays only what the storage has not flushed
+    let fsync = FsyncPolicy::Interval(Duration::from_secs(1));
+    let mut wal = WriteAheadLog::open("metrics-wal", TIME_UNIT, fsync).unwrap();
+    wal.set_checkpoint_bytes(Some(1 << 20));
+    consumer.set_wal(Some(wal));
+    consumer.replay_wal().await.unwrap();
     let key = String::from("a");
     {
         let mut queue = consumer.push(&key);
@@ -94,60 +130,59 @@ async fn main() {
             queue(Sample {
                 time: i,
                 value: i as f64,
-            });
+            })
+            .unwrap();
         }
     }
 
-    enum ConsumerMessage {
-        Chart(ChartQuery, tokio::sync::oneshot::Sender<String>),
-    }
-    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-    tokio::spawn(async move {
-        let flush_interval = Duration::from_secs(1);
-        loop {
-            tokio::select! {
-                () = tokio::time::sleep(flush_interval) => exporter.flush(&mut consumer).await,
-                Some(msg) = rx.recv() => handle_msg(msg, &consumer).await,
+    let consumer = Arc::new(SharedMetricConsumer::new(consumer));
+    tokio::spawn({
+        let consumer = consumer.clone();
+        async move {
+            let flush_interval = Duration::from_secs(1);
+            loop {
+                tokio::time::sleep(flush_interval).await;
+                let mut locked = consumer.lock().await;
+                exporter.flush(&mut locked).await;
+                locked.sweep();
+                // The log holds the samples of the series that fail until they are retried on the next flush
+                if let Err(e) = locked.sync_wal() {
+                    eprintln!("{e}");
+                }
+                if let Err(e) = locked.flush_storage() {
+                    eprintln!("{e}");
+                }
+                locked.publish();
             }
         }
-        async fn handle_msg(msg: ConsumerMessage, consumer: &MetricConsumer) {
-            match msg {
-                ConsumerMessage::Chart(query, resp) => {
-                    let keys = query.keys.split(',');
-                    let y_range = query.y_range.and_then(|s| {
-                        let (a, b) = s.split_once(',')?;
-                        let a = a.parse().ok()?;
-                        let b = b.parse().ok()?;
-                        Some((a, b))
-                    });
-                    let zone = query.zone_offset.as_deref();
-                    let start = query.start.as_ref().and_then(|s| parse_human_time(s, zone));
-                    let end = query.end.as_ref().and_then(|s| parse_human_time(s, zone));
-                    let time_range: RangeAny<Time> = match (start, end) {
-                        (None, None) => (..).into(),
-                        (Some(start), None) => (start..).into(),
-                        (Some(start), Some(end)) => (start..=end).into(),
-                        (None, Some(end)) => (..=end).into(),
-                    };
-                    let html = scatter_chart_html(
-                        consumer.metrics(),
-                        &HashMap::new(),
-                        keys,
-                        time_range,
-                        y_range,
-                        None,
-                    )
-                    .await;
-                    resp.send(html).unwrap();
+    });
+    tokio::spawn({
+        let consumer = consumer.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = compact_storage(&consumer).await {
+                    eprintln!("{e}");
                 }
             }
         }
     });
 
+    let mut syntheses: MetricSyntheses = HashMap::new();
+    syntheses.insert(
+        "mem.usage".into(),
+        Box::new(Combine::ratio(vec!["mem.used".into(), "mem.total".into()])),
+    );
+
     struct AppState {
-        pub consumer: tokio::sync::mpsc::Sender<ConsumerMessage>,
+        pub consumer: Arc<SharedMetricConsumer>,
+        pub syntheses: MetricSyntheses,
     }
-    let state = Arc::new(AppState { consumer: tx });
+    let state = Arc::new(AppState {
+        consumer,
+        syntheses,
+    });
 
     #[derive(Deserialize)]
     struct ChartQuery {
@@ -160,10 +195,33 @@ async fn main() {
 
     #[handler]
     async fn chart(query: Query<ChartQuery>, state: Data<&Arc<AppState>>) -> Html<String> {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        let msg = ConsumerMessage::Chart(query.0, tx);
-        state.consumer.send(msg).await.unwrap();
-        let chart = rx.await.unwrap();
+        let query = query.0;
+        let keys = query.keys.split(',');
+        let y_range = query.y_range.and_then(|s| {
+            let (a, b) = s.split_once(',')?;
+            let a = a.parse().ok()?;
+            let b = b.parse().ok()?;
+            Some((a, b))
+        });
+        let zone = query.zone_offset.as_deref();
+        let start = query.start.as_ref().and_then(|s| parse_human_time(s, zone));
+        let end = query.end.as_ref().and_then(|s| parse_human_time(s, zone));
+        let time_range: RangeAny<Time> = match (start, end) {
+            (None, None) => (..).into(),
+            (Some(start), None) => (start..).into(),
+            (Some(start), Some(end)) => (start..=end).into(),
+            (None, Some(end)) => (..=end).into(),
+        };
+        let chart = scatter_chart_html(
+            &state.consumer.metrics(),
+            &state.syntheses,
+            keys,
+            time_range,
+            state.consumer.time_unit(),
+            y_range,
+            None,
+        )
+        .await;
         let chart = danger(chart);
         let math_jax = "https://cdn.jsdelivr.net/npm/mathjax@3.2.2/es5/tex-svg.js";
         let math_jax = script(()).src(math_jax);
@@ -181,18 +239,36 @@ async fn main() {
     let listener = poem::listener::TcpListener::bind("0.0.0.0:3000");
     println!("- a: <http://127.0.0.1:3000/?keys=a&start=0&end=15>");
     println!("- usage: <http://127.0.0.1:3000/?y_range=0,1&keys=cpu,mem,swap>");
-    println!("- mem: <http://127.0.0.1:3000/?keys=mem.free,mem.available,mem.used,mem.total>");
+    println!("- mem: <http://127.0.0.1:3000/?keys=mem.*>");
+    println!("- mem usage: <http://127.0.0.1:3000/?y_range=0,1&keys=mem.usage>");
+    println!("- swap usage: <http://127.0.0.1:3000/?y_range=0,100&keys=100*swap.used/swap.total>");
     println!("- swap: <http://127.0.0.1:3000/?keys=swap.free,swap.used,swap.total>");
     Server::new(listener).run(app).await.unwrap();
 }
 
-fn system_time_timestamp(sys_time: SystemTime) -> u64 {
-    u64::try_from(sys_time.duration_since(UNIX_EPOCH).unwrap().as_millis()).unwrap()
+/// Holds the consumer only to plan and to finish, not while the segments are rewritten
+async fn compact_storage(consumer: &SharedMetricConsumer) -> std::io::Result<()> {
+    let Some(mut compaction) = consumer.lock().await.plan_storage_compaction()? else {
+        return Ok(());
+    };
+    let (compaction, res) = tokio::task::spawn_blocking(move || {
+        let res = compaction.run();
+        (compaction, res)
+    })
+    .await
+    .unwrap();
+    consumer
+        .lock()
+        .await
+        .finish_storage_compaction(compaction)?;
+    res
 }
-fn duration_timestamp_diff(diff: Diff<Duration>) -> Option<Diff<u64>> {
-    diff.map(|x| u64::try_from(x.as_millis()))
-        .transpose_result()
-        .ok()
+
+fn system_time_timestamp(sys_time: SystemTime) -> Time {
+    TIME_UNIT.from_duration(sys_time.duration_since(UNIX_EPOCH).unwrap())
+}
+fn duration_timestamp_diff(diff: Diff<Duration>) -> Diff<Time> {
+    diff.map(|x| TIME_UNIT.from_duration(x))
 }
 
 fn parse_human_duration_diff(s: &str) -> Option<Diff<Duration>> {
@@ -207,13 +283,12 @@ fn parse_human_duration_diff(s: &str) -> Option<Diff<Duration>> {
 }
 fn parse_human_time(s: &str, zone_offset: Option<&str>) -> Option<Time> {
     if let Some(diff) = parse_human_duration_diff(s) {
-        let time = system_time_timestamp(SystemTime::now());
-        let diff = duration_timestamp_diff(diff)?;
-        return time.add_diff(diff);
+        let time = SystemClock.timestamp(TIME_UNIT);
+        return time.add_diff(duration_timestamp_diff(diff));
     }
     let zone_offset = zone_offset
         .and_then(parse_human_duration_diff)
-        .and_then(duration_timestamp_diff);
+        .map(duration_timestamp_diff);
     let utc = humantime::parse_rfc3339_weak(s)
         .ok()
         .map(system_time_timestamp);
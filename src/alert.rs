@@ -1,7 +1,10 @@
+use std::time::Duration;
+
 use crate::{
+    clock::Clock,
     consumer::MetricQueues,
     view::{metric_span, MetricSyntheses},
-    MetricKey, Sample, Time,
+    MetricKey, Sample, Time, TimeUnit,
 };
 
 pub struct MetricAlerter {
@@ -13,7 +16,24 @@ impl MetricAlerter {
     pub fn new(from: Time, key: MetricKey, trip: Box<dyn Fn(Sample) -> bool>) -> Self {
         Self { from, key, trip }
     }
+    /// Only samples no older than `lookback` from now are evaluated
+    pub fn since(
+        clock: &dyn Clock,
+        time_unit: TimeUnit,
+        lookback: Duration,
+        key: MetricKey,
+        trip: Box<dyn Fn(Sample) -> bool>,
+    ) -> Self {
+        let now = clock.timestamp(time_unit);
+        let lookback = time_unit.from_duration(lookback);
+        Self::new(now.saturating_sub(lookback), key, trip)
+    }
 
+    pub fn key(&self) -> &MetricKey {
+        &self.key
+    }
+
+    /// [`None`] if the series does not exist, e.g. after a [`crate::consumer::SeriesExpired`]
     pub fn alert(&mut self, metrics: &MetricQueues, syntheses: &MetricSyntheses) -> Option<bool> {
         let time_range = self.from..;
         let span = metric_span(metrics, syntheses, &self.key, time_range)?;
@@ -45,3 +65,36 @@ impl core::fmt::Debug for MetricAlerter {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{clock::MockClock, consumer::MetricConsumer};
+
+    use super::*;
+
+    #[test]
+    fn test_since() {
+        let clock = MockClock::new(Duration::from_secs(100));
+        let mut consumer = MetricConsumer::with_time_unit(16, TimeUnit::Seconds);
+        let key: MetricKey = "a".into();
+        for (time, value) in [(50, 9.), (95, 1.), (99, 2.)] {
+            consumer.push(&key)(Sample { time, value }).unwrap();
+        }
+        let lookback = Duration::from_secs(10);
+        let trip = Box::new(|sample: Sample| 5. < sample.value);
+        let mut alerter =
+            MetricAlerter::since(&clock, TimeUnit::Seconds, lookback, key.clone(), trip);
+        let syntheses = MetricSyntheses::new();
+        // The sample at 50 is past the lookback
+        assert_eq!(alerter.alert(consumer.metrics(), &syntheses), Some(false));
+        consumer.push(&key)(Sample {
+            time: 101,
+            value: 6.,
+        })
+        .unwrap();
+        assert_eq!(alerter.alert(consumer.metrics(), &syntheses), Some(true));
+        // Only new samples are evaluated
+        assert_eq!(alerter.alert(consumer.metrics(), &syntheses), Some(false));
+        assert_eq!(alerter.alert(&Default::default(), &syntheses), None);
+    }
+}
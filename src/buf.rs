@@ -5,7 +5,7 @@ use primitive::{
     sync::mcast::{MpMcast, MpMcastReader},
 };
 
-use crate::{MetricKey, Sample};
+use crate::{clock::Clock, MetricKey, Sample, TimeUnit};
 
 pub const BUF_SIZE: usize = 1024;
 pub type MetricBuf = MpMcast<Sample, BUF_SIZE>;
@@ -14,10 +14,18 @@ pub type MetricBufReader = MpMcastReader<Sample, BUF_SIZE, Arc<MetricBuf>>;
 #[derive(Debug, Clone)]
 pub struct MetricBufReaders {
     readers: Vec<(MetricKey, MetricBufReader)>,
+    time_unit: TimeUnit,
 }
 impl MetricBufReaders {
     pub fn new() -> Self {
-        Self { readers: vec![] }
+        Self::with_time_unit(TimeUnit::default())
+    }
+    /// Samples pushed to the buffers are expected to be timed in `time_unit`
+    pub fn with_time_unit(time_unit: TimeUnit) -> Self {
+        Self {
+            readers: vec![],
+            time_unit,
+        }
     }
     pub fn new_metrics(&mut self, key: MetricKey) -> Arc<MetricBuf> {
         let queue = MetricBuf::new();
@@ -26,12 +34,50 @@ impl MetricBufReaders {
         self.readers.push((key, reader));
         queue
     }
+    pub fn new_handle(&mut self, key: MetricKey, clock: Arc<dyn Clock>) -> MetricHandle {
+        let buf = self.new_metrics(key);
+        MetricHandle::new(buf, clock, self.time_unit)
+    }
     pub fn readers_mut(&mut self) -> &mut [(MetricKey, MetricBufReader)] {
         &mut self.readers
     }
+    pub fn time_unit(&self) -> TimeUnit {
+        self.time_unit
+    }
 }
 impl Default for MetricBufReaders {
     fn default() -> Self {
         Self::new()
     }
 }
+
+/// Stamps each value with the time read from its clock
+#[derive(Debug, Clone)]
+pub struct MetricHandle {
+    buf: Arc<MetricBuf>,
+    clock: Arc<dyn Clock>,
+    time_unit: TimeUnit,
+}
+impl MetricHandle {
+    pub fn new(buf: Arc<MetricBuf>, clock: Arc<dyn Clock>, time_unit: TimeUnit) -> Self {
+        Self {
+            buf,
+            clock,
+            time_unit,
+        }
+    }
+
+    pub fn push(&self, value: f64) {
+        let time = self.clock.timestamp(self.time_unit);
+        self.buf.try_push(Sample { time, value });
+    }
+    pub fn buf(&self) -> &Arc<MetricBuf> {
+        &self.buf
+    }
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+    pub fn time_unit(&self) -> TimeUnit {
+        self.time_unit
+    }
+}
@@ -0,0 +1,334 @@
+//! A deque stored in chunks that are shared between its clones
+//!
+//! Cloning copies only the pointers to the chunks, and a write afterwards copies only the chunk it lands in.
+
+use std::{collections::VecDeque, ops::Range, sync::Arc};
+
+/// Number of elements a chunk is filled up to by [`ChunkedDeque::push_back`]
+pub const CHUNK_SIZE: usize = 256;
+
+#[derive(Debug, Clone)]
+struct Chunk<T> {
+    /// Index of the first element counted from the first element ever pushed
+    start: usize,
+    elements: Arc<Vec<T>>,
+}
+impl<T> Chunk<T> {
+    fn end(&self) -> usize {
+        self.start + self.elements.len()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkedDeque<T> {
+    chunks: VecDeque<Chunk<T>>,
+    /// Index of the first element counted from the first element ever pushed
+    ///
+    /// The elements popped from the first chunk stay there until all of it is popped.
+    front: usize,
+    len: usize,
+}
+impl<T: Clone> ChunkedDeque<T> {
+    pub fn new() -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            front: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn front(&self) -> Option<&T> {
+        self.get(0)
+    }
+    pub fn back(&self) -> Option<&T> {
+        self.chunks.back()?.elements.last()
+    }
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.span(0..self.len).get(index)
+    }
+    /// Copies the chunk of the element if it is shared
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if self.len <= index {
+            return None;
+        }
+        let (chunk, offset) = locate(&self.chunks, self.front + index);
+        Arc::make_mut(&mut self.chunks[chunk].elements).get_mut(offset)
+    }
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> + Clone + '_ {
+        self.span(0..self.len).iter()
+    }
+    /// Elements at the indices in `range`
+    pub fn span(&self, range: Range<usize>) -> ChunkedSpan<'_, T> {
+        let end = range.end.min(self.len);
+        let start = range.start.min(end);
+        ChunkedSpan {
+            chunks: &self.chunks,
+            start: self.front + start,
+            end: self.front + end,
+        }
+    }
+    /// The number of elements for which `pred` holds, given that it holds for a prefix of the deque
+    pub fn partition_point(&self, pred: impl FnMut(&T) -> bool) -> usize {
+        self.span(0..self.len).partition_point(pred)
+    }
+
+    pub fn push_back(&mut self, value: T) {
+        match self.chunks.back_mut() {
+            Some(chunk) if chunk.elements.len() < CHUNK_SIZE => {
+                Arc::make_mut(&mut chunk.elements).push(value)
+            }
+            _ => {
+                let mut elements = Vec::with_capacity(CHUNK_SIZE);
+                elements.push(value);
+                self.chunks.push_back(Chunk {
+                    start: self.front + self.len,
+                    elements: Arc::new(elements),
+                });
+            }
+        }
+        self.len += 1;
+    }
+    /// Never copies a chunk
+    pub fn pop_front(&mut self) -> Option<T> {
+        let value = self.front()?.clone();
+        self.front += 1;
+        self.len -= 1;
+        if self.chunks[0].end() == self.front {
+            self.chunks.pop_front();
+        }
+        Some(value)
+    }
+    /// Copies the chunk the element lands in if it is shared and splits it once it doubles [`CHUNK_SIZE`]
+    pub fn insert(&mut self, index: usize, value: T) {
+        assert!(index <= self.len, "index out of bounds");
+        if index == self.len {
+            self.push_back(value);
+            return;
+        }
+        self.trim_front();
+        let (i, offset) = locate(&self.chunks, self.front + index);
+        let chunk = &mut self.chunks[i];
+        let elements = Arc::make_mut(&mut chunk.elements);
+        elements.insert(offset, value);
+        let split = (2 * CHUNK_SIZE <= elements.len()).then(|| {
+            let rest = elements.split_off(elements.len() / 2);
+            Chunk {
+                start: chunk.start + elements.len(),
+                elements: Arc::new(rest),
+            }
+        });
+        for chunk in self.chunks.range_mut(i + 1..) {
+            chunk.start += 1;
+        }
+        if let Some(split) = split {
+            self.chunks.insert(i + 1, split);
+        }
+        self.len += 1;
+    }
+    /// Keeps the chunks with nothing removed as they are and drops the ones left empty
+    pub fn retain(&mut self, mut f: impl FnMut(&T) -> bool) {
+        self.trim_front();
+        let mut start = self.front;
+        let mut chunks = VecDeque::with_capacity(self.chunks.len());
+        for mut chunk in self.chunks.drain(..) {
+            let kept = chunk.elements.iter().map(&mut f).collect::<Vec<bool>>();
+            if !kept.iter().all(|&kept| kept) {
+                let elements = chunk.elements.iter().zip(kept);
+                let elements = elements.filter(|(_, kept)| *kept);
+                chunk.elements = Arc::new(elements.map(|(value, _)| value.clone()).collect());
+            }
+            chunk.start = start;
+            start += chunk.elements.len();
+            if !chunk.elements.is_empty() {
+                chunks.push_back(chunk);
+            }
+        }
+        self.chunks = chunks;
+        self.len = start - self.front;
+    }
+    /// Removes the elements at the indices in `range`
+    pub fn remove_range(&mut self, range: Range<usize>) {
+        let mut index = 0;
+        self.retain(|_| {
+            let kept = !range.contains(&index);
+            index += 1;
+            kept
+        });
+    }
+
+    /// Drops the popped elements left in the first chunk
+    fn trim_front(&mut self) {
+        let Some(chunk) = self.chunks.front_mut() else {
+            return;
+        };
+        if chunk.start == self.front {
+            return;
+        }
+        Arc::make_mut(&mut chunk.elements).drain(..self.front - chunk.start);
+        chunk.start = self.front;
+    }
+}
+impl<T: Clone> Default for ChunkedDeque<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T: Clone> FromIterator<T> for ChunkedDeque<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut deque = Self::new();
+        for value in iter {
+            deque.push_back(value);
+        }
+        deque
+    }
+}
+impl<T: Clone> core::ops::Index<usize> for ChunkedDeque<T> {
+    type Output = T;
+    fn index(&self, index: usize) -> &T {
+        self.get(index).expect("index out of bounds")
+    }
+}
+impl<T: Clone + PartialEq> PartialEq for ChunkedDeque<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+/// Chunk holding the element at `index`, counted from the first element ever pushed, and the offset into it
+fn locate<T>(chunks: &VecDeque<Chunk<T>>, index: usize) -> (usize, usize) {
+    let i = chunks.partition_point(|chunk| chunk.start <= index) - 1;
+    (i, index - chunks[i].start)
+}
+
+/// Consecutive elements of a [`ChunkedDeque`], borrowed in place
+#[derive(Debug)]
+pub struct ChunkedSpan<'a, T> {
+    chunks: &'a VecDeque<Chunk<T>>,
+    /// Indices counted from the first element ever pushed
+    start: usize,
+    end: usize,
+}
+impl<T> Clone for ChunkedSpan<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for ChunkedSpan<'_, T> {}
+impl<'a, T> ChunkedSpan<'a, T> {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+    /// The elements in runs that are contiguous in memory
+    pub fn slices(&self) -> impl DoubleEndedIterator<Item = &'a [T]> + Clone + 'a {
+        let (start, end) = (self.start, self.end);
+        let first = self.chunks.partition_point(|chunk| chunk.end() <= start);
+        let last = self.chunks.partition_point(|chunk| chunk.start < end);
+        let chunks = match self.is_empty() {
+            true => self.chunks.range(0..0),
+            false => self.chunks.range(first..last),
+        };
+        chunks.map(move |chunk| {
+            let from = start.saturating_sub(chunk.start);
+            let to = end.min(chunk.end()) - chunk.start;
+            &chunk.elements[from..to]
+        })
+    }
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &'a T> + Clone + 'a {
+        self.slices().flatten()
+    }
+    pub fn first(&self) -> Option<&'a T> {
+        self.get(0)
+    }
+    pub fn last(&self) -> Option<&'a T> {
+        self.get(self.len().checked_sub(1)?)
+    }
+    pub fn get(&self, index: usize) -> Option<&'a T> {
+        if self.len() <= index {
+            return None;
+        }
+        let (chunk, offset) = locate(self.chunks, self.start + index);
+        self.chunks[chunk].elements.get(offset)
+    }
+    /// The number of elements for which `pred` holds, given that it holds for a prefix of the span
+    pub fn partition_point(&self, mut pred: impl FnMut(&T) -> bool) -> usize {
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match pred(self.get(mid).unwrap()) {
+                true => lo = mid + 1,
+                false => hi = mid,
+            }
+        }
+        lo
+    }
+    /// Elements at the indices in `range` of the span
+    pub fn sub_span(&self, range: Range<usize>) -> Self {
+        let end = range.end.min(self.len());
+        let start = range.start.min(end);
+        Self {
+            chunks: self.chunks,
+            start: self.start + start,
+            end: self.start + end,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(deque: &ChunkedDeque<usize>) -> Vec<usize> {
+        deque.iter().copied().collect()
+    }
+
+    #[test]
+    fn test_clone_shares_chunks() {
+        let mut deque: ChunkedDeque<usize> = (0..3 * CHUNK_SIZE).collect();
+        let published = deque.clone();
+        deque.push_back(3 * CHUNK_SIZE);
+        *deque.get_mut(1).unwrap() = 0;
+        let shared = deque.chunks.iter().zip(&published.chunks);
+        let shared = shared.filter(|(a, b)| Arc::ptr_eq(&a.elements, &b.elements));
+        assert_eq!(shared.count(), 2);
+        assert_eq!(published[1], 1);
+        assert_eq!(deque[1], 0);
+    }
+
+    #[test]
+    fn test_edit_across_chunks() {
+        let mut deque: ChunkedDeque<usize> = (0..2 * CHUNK_SIZE).map(|i| 2 * i).collect();
+        for _ in 0..10 {
+            deque.pop_front();
+        }
+        let mut expected = values(&deque);
+        for value in [21, 2 * CHUNK_SIZE + 1, 4 * CHUNK_SIZE + 1] {
+            let pos = deque.partition_point(|&other| other < value);
+            deque.insert(pos, value);
+            expected.insert(expected.partition_point(|&other| other < value), value);
+        }
+        assert_eq!(values(&deque), expected);
+        deque.retain(|value| value % 3 != 0);
+        expected.retain(|value| value % 3 != 0);
+        assert_eq!(values(&deque), expected);
+        let span = deque.span(5..300);
+        assert_eq!(span.len(), 295);
+        assert!(span.iter().eq(&expected[5..300]));
+        assert!(span.iter().rev().eq(expected[5..300].iter().rev()));
+        assert_eq!(span.sub_span(10..20).first(), Some(&expected[15]));
+        let below = expected[5..300].partition_point(|&value| value < 400);
+        assert_eq!(span.partition_point(|&value| value < 400), below);
+        for _ in 0..expected.len() {
+            deque.pop_front();
+        }
+        assert!(deque.is_empty() && deque.chunks.is_empty());
+    }
+}
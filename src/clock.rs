@@ -0,0 +1,82 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Time, TimeUnit};
+
+pub trait Clock: core::fmt::Debug + Sync + Send {
+    /// Duration since the Unix epoch
+    fn now(&self) -> Duration;
+
+    fn timestamp(&self, unit: TimeUnit) -> Time {
+        unit.from_duration(self.now())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+}
+
+/// Reads the system time once and advances it by [`Instant`] afterwards, so it never goes backwards
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock {
+    anchor_system: Duration,
+    anchor_instant: Instant,
+}
+impl MonotonicClock {
+    pub fn new() -> Self {
+        let anchor_instant = Instant::now();
+        let anchor_system = SystemClock.now();
+        Self {
+            anchor_system,
+            anchor_instant,
+        }
+    }
+}
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.anchor_system + self.anchor_instant.elapsed()
+    }
+}
+
+/// Only moves when told to
+///
+/// Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    nanos: Arc<AtomicU64>,
+}
+impl MockClock {
+    pub fn new(now: Duration) -> Self {
+        let clock = Self::default();
+        clock.set(now);
+        clock
+    }
+
+    pub fn set(&self, now: Duration) {
+        let nanos = u64::try_from(now.as_nanos()).unwrap();
+        self.nanos.store(nanos, Ordering::SeqCst);
+    }
+    pub fn advance(&self, by: Duration) {
+        let nanos = u64::try_from(by.as_nanos()).unwrap();
+        self.nanos.fetch_add(nanos, Ordering::SeqCst);
+    }
+}
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
@@ -1,8 +1,8 @@
 use std::io::{self, Read, Write};
 
-use crate::{MetricKey, Sample, SAMPLE_SIZE};
+use crate::{MetricKey, Sample, TimeUnit, SAMPLE_SIZE};
 
-pub fn encode_key(wtr: &mut impl Write, key: &MetricKey) {
+pub fn encode_key(wtr: &mut impl Write, key: &str) {
     let len = u16::try_from(key.len()).unwrap();
     wtr.write_all(&len.to_be_bytes()).unwrap();
     wtr.write_all(key.as_bytes()).unwrap();
@@ -23,6 +23,47 @@ where
     Ok(())
 }
 
+/// Leads every frame and changes whenever the frame layout does
+pub const FRAME_VERSION: u8 = 1;
+
+pub fn encode_frame_version() -> [u8; 1] {
+    [FRAME_VERSION]
+}
+/// Fails with [`io::ErrorKind::Unsupported`] on any other version, after which the rest of the stream cannot be framed
+pub fn decode_frame_version(buf: [u8; 1]) -> io::Result<()> {
+    if buf[0] != FRAME_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unknown frame version {}", buf[0]),
+        ));
+    }
+    Ok(())
+}
+
+pub fn encode_time_unit(unit: TimeUnit) -> [u8; 1] {
+    let unit = match unit {
+        TimeUnit::Seconds => 0,
+        TimeUnit::Millis => 1,
+        TimeUnit::Micros => 2,
+        TimeUnit::Nanos => 3,
+    };
+    [unit]
+}
+pub fn decode_time_unit(buf: [u8; 1]) -> io::Result<TimeUnit> {
+    Ok(match buf[0] {
+        0 => TimeUnit::Seconds,
+        1 => TimeUnit::Millis,
+        2 => TimeUnit::Micros,
+        3 => TimeUnit::Nanos,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown time unit",
+            ))
+        }
+    })
+}
+
 pub fn encode_sample_count(count: u16) -> [u8; 2] {
     count.to_be_bytes()
 }
@@ -47,3 +88,121 @@ pub fn decode_sample(buf: [u8; SAMPLE_SIZE]) -> Sample {
     let value = f64::from_be_bytes(value);
     Sample { time, value }
 }
+
+/// Appends `samples` to `buf` as frames of at most [`u16::MAX`] samples each
+pub fn encode_frames(
+    buf: &mut Vec<u8>,
+    key: &MetricKey,
+    time_unit: TimeUnit,
+    samples: impl Iterator<Item = Sample>,
+) {
+    let mut samples = samples.peekable();
+    while samples.peek().is_some() {
+        buf.extend(encode_frame_version());
+        encode_key(buf, key);
+        buf.extend(encode_time_unit(time_unit));
+        let count_pos = buf.len();
+        buf.extend(encode_sample_count(0));
+        let mut count: u16 = 0;
+        for sample in samples.by_ref().take(usize::from(u16::MAX)) {
+            buf.extend(encode_sample(sample));
+            count += 1;
+        }
+        buf[count_pos..count_pos + 2].copy_from_slice(&encode_sample_count(count));
+    }
+}
+
+/// Length and CRC of the frame that follows
+const CHECKED_FRAME_HEADER_SIZE: usize = 4 + 4;
+/// Version, key, time unit, sample count and samples
+const MAX_FRAME_SIZE: usize = 1 + 2 + u16::MAX as usize + 1 + 2 + u16::MAX as usize * SAMPLE_SIZE;
+
+/// Appends `samples` as [`encode_frames`] does, with each frame checked as by [`encode_checked`]
+///
+/// For frames kept at rest, where a corrupt one should be caught before it is decoded.
+pub fn encode_checked_frames(
+    buf: &mut Vec<u8>,
+    key: &MetricKey,
+    time_unit: TimeUnit,
+    samples: impl Iterator<Item = Sample>,
+) {
+    let mut samples = samples.peekable();
+    while samples.peek().is_some() {
+        let samples = samples.by_ref().take(usize::from(u16::MAX));
+        encode_checked(buf, |buf| encode_frames(buf, key, time_unit, samples));
+    }
+}
+/// Reads a frame written by [`encode_checked_frames`] into `frame`
+///
+/// See [`read_checked`].
+pub async fn read_checked_frame<R>(rdr: &mut R, frame: &mut Vec<u8>) -> io::Result<bool>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    read_checked(rdr, frame, MAX_FRAME_SIZE).await
+}
+/// Appends what `encode` writes led by its length and a CRC of it
+pub fn encode_checked(buf: &mut Vec<u8>, encode: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.extend([0; CHECKED_FRAME_HEADER_SIZE]);
+    encode(buf);
+    let payload = &buf[start + CHECKED_FRAME_HEADER_SIZE..];
+    let len = u32::try_from(payload.len()).unwrap();
+    let crc = crc32fast::hash(payload);
+    buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+    buf[start + 4..start + 8].copy_from_slice(&crc.to_be_bytes());
+}
+/// Reads what [`encode_checked`] wrote into `payload`
+///
+/// Returns `false` at the end of the stream.
+/// Fails with [`io::ErrorKind::InvalidData`] on a CRC mismatch or a length past `max_len`.
+pub async fn read_checked<R>(rdr: &mut R, payload: &mut Vec<u8>, max_len: usize) -> io::Result<bool>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+    let mut header = [0; CHECKED_FRAME_HEADER_SIZE];
+    let n = rdr.read(&mut header).await?;
+    if n == 0 {
+        return Ok(false);
+    }
+    rdr.read_exact(&mut header[n..]).await?;
+    let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_be_bytes(header[4..].try_into().unwrap());
+    if max_len < len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt record"));
+    }
+    payload.clear();
+    payload.resize(len, 0);
+    rdr.read_exact(payload).await?;
+    if crc32fast::hash(payload) != crc {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt record"));
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{consumer::MetricConsumer, exporter::decode_frame_copy};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_version() {
+        let mut buf = vec![];
+        let samples = [Sample { time: 1, value: 2. }];
+        encode_frames(&mut buf, &"a".into(), TimeUnit::Millis, samples.into_iter());
+        let mut consumer = MetricConsumer::new(16);
+        let mut key = String::new();
+        decode_frame_copy(&mut &buf[..], &mut consumer, &mut key)
+            .await
+            .unwrap();
+        assert_eq!(consumer.metrics()["a"].len(), 1);
+
+        buf[0] = FRAME_VERSION + 1;
+        let err = decode_frame_copy(&mut &buf[..], &mut consumer, &mut key)
+            .await
+            .unwrap_err();
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -1,85 +1,1380 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, VecDeque},
+    io,
+    ops::{Bound, RangeInclusive},
+    sync::Arc,
+    time::Duration,
+};
 
-use primitive::map::hash_map::HashEnsure;
+use primitive::ops::range::RangeAny;
+use tokio::sync::broadcast;
 
-use crate::{MetricKey, Sample, Time};
+use crate::{
+    chunked::{ChunkedDeque, ChunkedSpan},
+    clock::{Clock, SystemClock},
+    codec::encode_checked_frames,
+    exporter::copy_checked_frames,
+    rollup::{Rollup, RollupBucket, RollupTier},
+    storage::{Compaction, DiskStore},
+    wal::{WalRecord, WriteAheadLog},
+    MetricKey, Sample, Time, TimeUnit, SAMPLE_SIZE,
+};
 
-pub type MetricQueues = HashMap<MetricKey, MetricQueue>;
+/// Queues are shared with the clones of the map until written to, so the map is cheap to clone
+///
+/// Kept in order of the keys so that [`KeyPattern::literal_prefix`] narrows a selection down to a range.
+pub type MetricQueues = BTreeMap<MetricKey, Arc<MetricQueue>>;
 
-#[derive(Debug, Clone)]
+const EXPIRY_EVENT_CAPACITY: usize = 1024;
+
+/// A clone is detached from the storage, the write-ahead log and the expiry subscribers
+#[derive(Debug)]
 pub struct MetricConsumer {
     metrics: MetricQueues,
     queue_size: usize,
+    time_unit: TimeUnit,
+    clock: Arc<dyn Clock>,
+    default_config: MetricQueueConfig,
+    /// Later entries take precedence
+    configs: Vec<(KeyPattern, MetricQueueConfig)>,
+    memory_budget: Option<MemoryBudget>,
+    /// Keys by [`eviction_rank`] while there is a memory budget, the first to be evicted first
+    eviction_order: BTreeMap<(Time, u64), MetricKey>,
+    /// Approximate bytes held by all queues
+    bytes: usize,
+    /// Number of samples ever written
+    writes: u64,
+    eviction_stats: EvictionStats,
+    cardinality: CardinalityLimits,
+    /// Number of keys under each of [`CardinalityLimits::prefixes`]
+    prefix_key_counts: Vec<usize>,
+    /// Refused new keys by the prefix they are blamed on, one of [`CardinalityLimits::prefixes`] or the empty one
+    refused_keys: HashMap<String, u64>,
+    /// Accepted samples are written through to it
+    storage: Option<DiskStore>,
+    /// Samples are journaled to it before reaching their queues
+    wal: Option<WriteAheadLog>,
+    expiry_events: Option<broadcast::Sender<SeriesExpired>>,
+}
+impl Clone for MetricConsumer {
+    fn clone(&self) -> Self {
+        Self {
+            metrics: self.metrics.clone(),
+            queue_size: self.queue_size,
+            time_unit: self.time_unit,
+            clock: self.clock.clone(),
+            default_config: self.default_config.clone(),
+            configs: self.configs.clone(),
+            memory_budget: self.memory_budget,
+            eviction_order: self.eviction_order.clone(),
+            bytes: self.bytes,
+            writes: self.writes,
+            eviction_stats: self.eviction_stats,
+            cardinality: self.cardinality.clone(),
+            prefix_key_counts: self.prefix_key_counts.clone(),
+            refused_keys: self.refused_keys.clone(),
+            storage: None,
+            wal: None,
+            expiry_events: None,
+        }
+    }
 }
 impl MetricConsumer {
     pub fn new(queue_size: usize) -> Self {
+        Self::with_time_unit(queue_size, TimeUnit::default())
+    }
+    /// Samples are stored timed in `time_unit`
+    pub fn with_time_unit(queue_size: usize, time_unit: TimeUnit) -> Self {
         Self {
-            metrics: HashMap::new(),
+            metrics: BTreeMap::new(),
             queue_size,
+            time_unit,
+            clock: Arc::new(SystemClock),
+            default_config: MetricQueueConfig::default(),
+            configs: vec![],
+            memory_budget: None,
+            eviction_order: BTreeMap::new(),
+            bytes: 0,
+            writes: 0,
+            eviction_stats: EvictionStats::default(),
+            cardinality: CardinalityLimits::default(),
+            prefix_key_counts: vec![],
+            refused_keys: HashMap::new(),
+            storage: None,
+            wal: None,
+            expiry_events: None,
         }
     }
 
-    pub fn push(&mut self, key: &MetricKey) -> impl FnMut(Sample) + use<'_> {
-        let queue = self.metrics.ensure(key, MetricQueue::new);
-        |sample: Sample| {
-            queue.push(sample, self.queue_size);
+    /// A new key is subject to the cardinality limits; see [`MetricConsumer::set_cardinality_limits`]
+    pub fn push<'a>(
+        &'a mut self,
+        key: &'a MetricKey,
+    ) -> impl FnMut(Sample) -> Result<(), PushError> + use<'a> {
+        let mut target = None;
+        move |sample: Sample| {
+            // The queue might have been evicted by an earlier sample of the same batch, and then it is new again
+            let admitted = match &target {
+                Some(Ok((key, _, _))) => self.metrics.contains_key(Cow::as_ref(key)),
+                Some(Err(_)) => true,
+                None => false,
+            };
+            if !admitted {
+                target = Some(self.admit(key).map(|key| {
+                    let config = self.queue_config(&key);
+                    let cutoff = self.retention_cutoff(&config);
+                    (key, config, cutoff)
+                }));
+            }
+            let (key, config, cutoff) = target.as_ref().unwrap().as_ref().map_err(|e| *e)?;
+            self.push_sample(key, config, *cutoff, sample)
+        }
+    }
+    fn push_sample(
+        &mut self,
+        key: &MetricKey,
+        config: &MetricQueueConfig,
+        cutoff: Option<Time>,
+        sample: Sample,
+    ) -> Result<(), PushError> {
+        if self.wal.as_ref().is_some_and(WriteAheadLog::checkpoint_due) {
+            self.checkpoint().map_err(|e| PushError::Wal(e.kind()))?;
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.append(key, &WalRecord::Sample(sample))
+                .map_err(|e| PushError::Wal(e.kind()))?;
+        }
+        self.writes += 1;
+        let queue_size = self.queue_size;
+        let before = self.metrics.get(key).map(|queue| {
+            let rank = eviction_rank(self.memory_budget, queue);
+            (queue_bytes(key, queue), rank)
+        });
+        let (before, rank) = before.unzip();
+        if before.is_none() {
+            let queue = MetricQueue::with_config(config.clone());
+            self.metrics.insert(key.clone(), Arc::new(queue));
+            self.count_prefix_keys(key, 1);
+        }
+        let queue = Arc::make_mut(self.metrics.get_mut(key).unwrap());
+        let res = queue.push(sample, queue_size);
+        if let Some(cutoff) = cutoff {
+            queue.evict_before(cutoff);
+        }
+        // Only what the queue keeps, so that loading the store brings back no more than that
+        let kept = cutoff.is_none_or(|cutoff| cutoff <= sample.time);
+        if let (Ok(pushed), Some(storage), true) = (res, &mut self.storage, kept) {
+            match pushed {
+                Pushed::Stored => storage.push(key, sample),
+                Pushed::Replaced(merged) => storage.replace(key, merged),
+                Pushed::Dropped => (),
+            }
+        }
+        queue.last_write = self.writes;
+        queue.last_write_time = self.clock.timestamp(self.time_unit);
+        self.bytes = self.bytes - before.unwrap_or(0) + queue_bytes(key, queue);
+        if let Some(new_rank) = eviction_rank(self.memory_budget, queue) {
+            let key = rank
+                .flatten()
+                .and_then(|rank| self.eviction_order.remove(&rank))
+                .unwrap_or_else(|| key.clone());
+            self.eviction_order.insert(new_rank, key);
+        }
+        self.enforce_memory_budget();
+        res.map(|_| ())
+    }
+    /// Evicts samples older than the retention of their queues and removes the idle queues
+    ///
+    /// Returns the number of evicted samples, not counting those of the removed queues.
+    pub fn sweep(&mut self) -> usize {
+        self.expire_idle_queues();
+        let mut evicted = 0;
+        for queue in self.metrics.values_mut() {
+            let cutoff = retention_cutoff(&*self.clock, self.time_unit, queue.config());
+            let Some(cutoff) = cutoff else {
+                continue;
+            };
+            // Left shared unless there is something to evict
+            if queue.buf.front().is_some_and(|front| front.time < cutoff) {
+                evicted += Arc::make_mut(queue).evict_before(cutoff);
+            }
+        }
+        self.bytes = self
+            .metrics
+            .iter()
+            .map(|(key, queue)| queue_bytes(key, queue))
+            .sum();
+        self.index_eviction_order();
+        self.enforce_memory_budget();
+        evicted
+    }
+    fn expire_idle_queues(&mut self) {
+        let now = self.clock.timestamp(self.time_unit);
+        let idle = |queue: &MetricQueue| {
+            let Some(ttl) = queue.config().idle_ttl else {
+                return false;
+            };
+            let ttl = self.time_unit.from_duration(ttl);
+            ttl < now.saturating_sub(queue.last_write_time)
+        };
+        let expired: Vec<MetricKey> = self
+            .metrics
+            .iter()
+            .filter(|(_, queue)| idle(queue))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            let queue = self.remove_queue(&key).unwrap();
+            if let Some(events) = &self.expiry_events {
+                let event = SeriesExpired {
+                    key,
+                    last_write_time: queue.last_write_time,
+                };
+                // No one is listening
+                let _ = events.send(event);
+            }
         }
     }
+    /// Receives a [`SeriesExpired`] for each queue [`MetricConsumer::sweep`] removes for being idle from now on
+    pub fn subscribe_expiry(&mut self) -> broadcast::Receiver<SeriesExpired> {
+        self.expiry_events
+            .get_or_insert_with(|| broadcast::channel(EXPIRY_EVENT_CAPACITY).0)
+            .subscribe()
+    }
+    fn retention_cutoff(&self, config: &MetricQueueConfig) -> Option<Time> {
+        retention_cutoff(&*self.clock, self.time_unit, config)
+    }
     pub fn metrics(&self) -> &MetricQueues {
         &self.metrics
     }
+    /// Keys of the queues matching `pattern` in order
+    pub fn keys<'a>(&'a self, pattern: &'a KeyPattern) -> impl Iterator<Item = &'a MetricKey> + 'a {
+        matching_keys(&self.metrics, pattern)
+    }
+    pub fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+    /// Existing queues do not shrink below the samples they already hold
+    pub fn set_queue_size(&mut self, queue_size: usize) {
+        self.queue_size = queue_size;
+    }
+    pub fn time_unit(&self) -> TimeUnit {
+        self.time_unit
+    }
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn storage(&self) -> Option<&DiskStore> {
+        self.storage.as_ref()
+    }
+    pub fn storage_mut(&mut self) -> Option<&mut DiskStore> {
+        self.storage.as_mut()
+    }
+    /// Returns the previously attached storage, if any
+    ///
+    /// Samples already in the queues are not written to the new storage; see [`MetricConsumer::load_storage`] to fill the queues from it.
+    pub fn set_storage(&mut self, storage: Option<DiskStore>) -> Option<DiskStore> {
+        core::mem::replace(&mut self.storage, storage)
+    }
+    /// Makes the samples accepted so far durable
+    ///
+    /// Once all of them are, the attached log is truncated, so that [`MetricConsumer::replay_wal`] after [`MetricConsumer::load_storage`] only pushes what the storage misses.
+    /// A [`crate::storage::FlushError`] comes wrapped in the [`io::Error`].
+    pub fn flush_storage(&mut self) -> io::Result<()> {
+        let Some(storage) = &mut self.storage else {
+            return Ok(());
+        };
+        storage.flush()?;
+        match &mut self.wal {
+            Some(wal) if !wal.is_empty() => wal.checkpoint(&[]),
+            _ => Ok(()),
+        }
+    }
+    /// Forces the records appended to the attached log to disk, as [`crate::wal::FsyncPolicy`] leaves the latest of them unsynced
+    pub fn sync_wal(&mut self) -> io::Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.sync(),
+            None => Ok(()),
+        }
+    }
+    pub fn wal(&self) -> Option<&WriteAheadLog> {
+        self.wal.as_ref()
+    }
+    pub fn wal_mut(&mut self) -> Option<&mut WriteAheadLog> {
+        self.wal.as_mut()
+    }
+    /// Returns the previously attached log, if any
+    ///
+    /// See [`MetricConsumer::replay_wal`] to restore the queues from it.
+    pub fn set_wal(&mut self, wal: Option<WriteAheadLog>) -> Option<WriteAheadLog> {
+        core::mem::replace(&mut self.wal, wal)
+    }
+    /// Pushes the latest checkpoint and then the journaled samples into the queues
+    ///
+    /// With storage attached, the journaled samples are the ones it has not flushed, and it takes them again.
+    pub async fn replay_wal(&mut self) -> io::Result<()> {
+        // Detached so that the replayed samples are not journaled again
+        let Some(mut wal) = self.wal.take() else {
+            return Ok(());
+        };
+        let res = self.replay_from(&mut wal).await;
+        self.wal = Some(wal);
+        res
+    }
+    async fn replay_from(&mut self, wal: &mut WriteAheadLog) -> io::Result<()> {
+        // Only taken with samples while there was no storage to flush instead
+        let frames = wal.checkpoint_frames()?;
+        let storage = self.storage.take();
+        let res = copy_checked_frames(&mut &frames[..], self).await;
+        self.storage = storage;
+        res?;
+        // Never already in the checkpoint; see `crate::wal`
+        for (key, record) in wal.records()? {
+            match record {
+                WalRecord::Sample(sample) => {
+                    let _ = self.push(&key)(sample);
+                }
+                WalRecord::Deletion(None) => {
+                    self.delete(&key)?;
+                }
+                WalRecord::Deletion(Some(range)) => match self.delete_range(&key, range) {
+                    Ok(_) => (),
+                    // The rollups were rebuilt from fewer samples than the deletion was checked against
+                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => (),
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+        Ok(())
+    }
+    /// Dumps all the queues and truncates the log, or with storage attached, flushes it instead of dumping
+    ///
+    /// Samples only kept in rollups are not part of the checkpoint.
+    /// Also taken on push once the log outgrows [`WriteAheadLog::checkpoint_bytes`].
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        if self.storage.is_some() {
+            return self.flush_storage();
+        }
+        let Some(wal) = &mut self.wal else {
+            return Ok(());
+        };
+        let mut frames = vec![];
+        for (key, queue) in &self.metrics {
+            let span = queue.span(..);
+            encode_checked_frames(&mut frames, key, self.time_unit, span.iter().copied());
+        }
+        wal.checkpoint(&frames)
+    }
+
+    /// Drops stored samples past the retention of their keys
+    pub fn compact_storage(&mut self) -> io::Result<()> {
+        let Some(mut compaction) = self.plan_storage_compaction()? else {
+            return Ok(());
+        };
+        let res = compaction.run();
+        self.finish_storage_compaction(compaction)?;
+        res
+    }
+    /// Plans [`MetricConsumer::compact_storage`] so that it can run without holding the consumer
+    ///
+    /// See [`DiskStore::plan_compaction`].
+    pub fn plan_storage_compaction(&mut self) -> io::Result<Option<Compaction>> {
+        let Some(storage) = &mut self.storage else {
+            return Ok(None);
+        };
+        let clock = &*self.clock;
+        let time_unit = self.time_unit;
+        let (default_config, configs) = (&self.default_config, &self.configs);
+        let compaction = storage.plan_compaction(|key| {
+            let config = queue_config(default_config, configs, key);
+            retention_cutoff(clock, time_unit, config)
+        })?;
+        Ok(Some(compaction))
+    }
+    /// Discarded if the storage is detached
+    pub fn finish_storage_compaction(&mut self, compaction: Compaction) -> io::Result<()> {
+        match &mut self.storage {
+            Some(storage) => storage.finish_compaction(compaction),
+            None => Ok(()),
+        }
+    }
+    /// Fills the queues with the latest stored samples still within retention and their rollups with all the stored samples
+    pub fn load_storage(&mut self) -> io::Result<()> {
+        // Detached so that the loaded samples are not written back or journaled
+        let Some(storage) = self.storage.take() else {
+            return Ok(());
+        };
+        let wal = self.wal.take();
+        let res = self.load_from(&storage);
+        self.storage = Some(storage);
+        self.wal = wal;
+        res
+    }
+    fn load_from(&mut self, storage: &DiskStore) -> io::Result<()> {
+        for key in storage.keys() {
+            let samples = storage.series(key).unwrap().read(..)?;
+            // The older samples only stay in the rollups, as with those pushed live
+            let mut push = self.push(key);
+            for sample in samples {
+                let _ = push(sample);
+            }
+        }
+        Ok(())
+    }
+
+    /// Approximate bytes held by all queues
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+    pub fn memory_budget(&self) -> Option<MemoryBudget> {
+        self.memory_budget
+    }
+    pub fn set_memory_budget(&mut self, budget: Option<MemoryBudget>) {
+        self.memory_budget = budget;
+        self.index_eviction_order();
+        self.enforce_memory_budget();
+    }
+    fn index_eviction_order(&mut self) {
+        self.eviction_order = self
+            .metrics
+            .iter()
+            .filter_map(|(key, queue)| {
+                Some((eviction_rank(self.memory_budget, queue)?, key.clone()))
+            })
+            .collect();
+    }
+    pub fn eviction_stats(&self) -> EvictionStats {
+        self.eviction_stats
+    }
+    /// Removes the queue of `key` along with its samples in the storage
+    ///
+    /// Returns whether the queue existed.
+    pub fn delete(&mut self, key: &str) -> io::Result<bool> {
+        if let Some(wal) = &mut self.wal {
+            wal.append(key, &WalRecord::Deletion(None))?;
+        }
+        if let Some(storage) = &mut self.storage {
+            storage.delete(key, 0..=Time::MAX)?;
+        }
+        Ok(self.remove_queue(key).is_some())
+    }
+    /// Deletes every key matching `pattern` in the queues or the storage
+    ///
+    /// Returns the number of deleted keys.
+    pub fn delete_matching(&mut self, pattern: &KeyPattern) -> io::Result<usize> {
+        let mut keys: Vec<MetricKey> = self.keys(pattern).cloned().collect();
+        if let Some(storage) = &self.storage {
+            let stored = storage.keys().filter(|key| pattern.matches(key));
+            keys.extend(stored.cloned());
+            keys.sort_unstable();
+            keys.dedup();
+        }
+        for key in &keys {
+            self.delete(key)?;
+        }
+        Ok(keys.len())
+    }
+    /// Deletes the samples of `key` within `range`, including those in the rollups and the storage
+    ///
+    /// Returns the number of raw samples deleted from the queue.
+    /// Fails with [`io::ErrorKind::InvalidInput`] without deleting anything where [`MetricQueue::delete`] refuses the range.
+    pub fn delete_range(
+        &mut self,
+        key: &str,
+        range: impl core::ops::RangeBounds<Time>,
+    ) -> io::Result<usize> {
+        let Some(range) = inclusive_range(range) else {
+            return Ok(0);
+        };
+        let queue = self.metrics.get(key);
+        if queue.is_some_and(|queue| !queue.can_delete(&range)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "deletion cuts through a rollup bucket with evicted samples",
+            ));
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.append(key, &WalRecord::Deletion(Some(range.clone())))?;
+        }
+        if let Some(storage) = &mut self.storage {
+            storage.delete(key, range.clone())?;
+        }
+        let Some(queue) = self.metrics.get_mut(key) else {
+            return Ok(0);
+        };
+        let before = queue.bytes();
+        let rank = eviction_rank(self.memory_budget, queue);
+        let deleted = Arc::make_mut(queue).delete(range).unwrap();
+        self.bytes = self.bytes - before + queue.bytes();
+        if let Some(rank) = rank {
+            let key = self.eviction_order.remove(&rank).unwrap();
+            let rank = eviction_rank(self.memory_budget, queue).unwrap();
+            self.eviction_order.insert(rank, key);
+        }
+        Ok(deleted)
+    }
+    /// Puts the buckets in place of those of the tiers of the same steps in the queue of `key`, which is created if missing
+    ///
+    /// The raw samples are taken to be missing up to `evicted_until`, as the buckets might summarize samples before them.
+    pub(crate) fn restore_rollups(
+        &mut self,
+        key: &MetricKey,
+        evicted_until: Option<Time>,
+        rollups: Vec<(Time, VecDeque<RollupBucket>)>,
+    ) {
+        let before = self.metrics.get(key).map(|queue| {
+            let rank = eviction_rank(self.memory_budget, queue);
+            (queue_bytes(key, queue), rank)
+        });
+        let (before, rank) = before.unzip();
+        if before.is_none() {
+            let queue = MetricQueue::with_config(self.queue_config(key));
+            self.metrics.insert(key.clone(), Arc::new(queue));
+            self.count_prefix_keys(key, 1);
+        }
+        let queue = Arc::make_mut(self.metrics.get_mut(key).unwrap());
+        queue.evicted_until = queue.evicted_until.max(evicted_until);
+        for (step, buckets) in rollups {
+            let rollup = queue
+                .rollups
+                .iter_mut()
+                .find(|rollup| rollup.tier().step == step);
+            if let Some(rollup) = rollup {
+                rollup.set_buckets(buckets);
+            }
+        }
+        self.bytes = self.bytes - before.unwrap_or(0) + queue_bytes(key, queue);
+        if let Some(new_rank) = eviction_rank(self.memory_budget, queue) {
+            let key = rank
+                .flatten()
+                .and_then(|rank| self.eviction_order.remove(&rank))
+                .unwrap_or_else(|| key.clone());
+            self.eviction_order.insert(new_rank, key);
+        }
+    }
+    /// Removes all the queues
+    pub fn clear(&mut self) {
+        self.metrics.clear();
+        self.eviction_order.clear();
+        self.bytes = 0;
+        self.prefix_key_counts
+            .iter_mut()
+            .for_each(|count| *count = 0);
+    }
+    fn remove_queue(&mut self, key: &str) -> Option<Arc<MetricQueue>> {
+        let (key, queue) = self.metrics.remove_entry(key)?;
+        if let Some(rank) = eviction_rank(self.memory_budget, &queue) {
+            self.eviction_order.remove(&rank);
+        }
+        self.bytes -= queue_bytes(&key, &queue);
+        self.count_prefix_keys(&key, -1);
+        Some(queue)
+    }
+    fn enforce_memory_budget(&mut self) {
+        let Some(budget) = self.memory_budget else {
+            return;
+        };
+        while budget.bytes < self.bytes {
+            let evicted = match budget.eviction {
+                Eviction::OldestSamples => self.evict_oldest_samples(budget.bytes),
+                Eviction::LeastRecentlyWritten => self.evict_least_recently_written(),
+            };
+            if !evicted {
+                break;
+            }
+        }
+    }
+    /// Pops from the queue holding the oldest sample until it no longer does or the budget is met
+    ///
+    /// The queue is removed once empty.
+    fn evict_oldest_samples(&mut self, budget: usize) -> bool {
+        let Some((_, key)) = self.eviction_order.pop_first() else {
+            return false;
+        };
+        let runner_up = match self.eviction_order.first_key_value() {
+            Some(((time, _), _)) => *time,
+            None => Time::MAX,
+        };
+        let queue = Arc::make_mut(self.metrics.get_mut(&key).unwrap());
+        while budget < self.bytes {
+            let Some(front) = queue.buf.front() else {
+                break;
+            };
+            if runner_up < front.time {
+                break;
+            }
+            queue.pop_front();
+            self.bytes -= SAMPLE_SIZE;
+            self.eviction_stats.samples += 1;
+        }
+        if queue.buf.is_empty() {
+            self.remove_queue(&key);
+            self.eviction_stats.keys += 1;
+        } else {
+            let rank = eviction_rank(self.memory_budget, queue).unwrap();
+            self.eviction_order.insert(rank, key);
+        }
+        true
+    }
+    fn evict_least_recently_written(&mut self) -> bool {
+        let Some((_, key)) = self.eviction_order.first_key_value() else {
+            return false;
+        };
+        let key = key.clone();
+        let queue = self.remove_queue(&key).unwrap();
+        self.eviction_stats.samples += queue.buf.len() as u64;
+        self.eviction_stats.keys += 1;
+        true
+    }
+
+    pub fn cardinality_limits(&self) -> &CardinalityLimits {
+        &self.cardinality
+    }
+    /// Only restricts keys created afterwards
+    pub fn set_cardinality_limits(&mut self, limits: CardinalityLimits) {
+        self.prefix_key_counts = limits
+            .prefixes
+            .iter()
+            .map(|(prefix, _)| {
+                self.metrics
+                    .keys()
+                    .filter(|key| key.starts_with(prefix.as_str()))
+                    .count()
+            })
+            .collect();
+        self.refused_keys.retain(|prefix, _| {
+            prefix.is_empty() || limits.prefixes.iter().any(|(limited, _)| limited == prefix)
+        });
+        self.cardinality = limits;
+    }
+    /// Prefixes with the most refused attempts to create a key, most first
+    ///
+    /// A refusal under the global limit is blamed on the longest matching limited prefix, or else the empty prefix.
+    pub fn top_refused_prefixes(&self, n: usize) -> Vec<(&str, u64)> {
+        let mut prefixes = self
+            .refused_keys
+            .iter()
+            .map(|(prefix, &count)| (prefix.as_str(), count))
+            .collect::<Vec<_>>();
+        prefixes.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        prefixes.truncate(n);
+        prefixes
+    }
+    /// Returns the key the samples of `key` should go to
+    fn admit<'a>(&mut self, key: &'a MetricKey) -> Result<Cow<'a, MetricKey>, PushError> {
+        if self.metrics.contains_key(key) {
+            return Ok(Cow::Borrowed(key));
+        }
+        let limits = &self.cardinality;
+        let full_prefix = limits
+            .prefixes
+            .iter()
+            .zip(&self.prefix_key_counts)
+            .find(|((prefix, max), count)| key.starts_with(prefix.as_str()) && *max <= **count);
+        let blamed = match full_prefix {
+            Some(((prefix, _), _)) => prefix.clone(),
+            None => {
+                let full = limits.max_keys.is_some_and(|max| max <= self.metrics.len());
+                if !full {
+                    return Ok(Cow::Borrowed(key));
+                }
+                // Blaming arbitrary keys would let the counts grow with them
+                let prefix = limits
+                    .prefixes
+                    .iter()
+                    .map(|(prefix, _)| prefix.as_str())
+                    .filter(|prefix| key.starts_with(prefix))
+                    .max_by_key(|prefix| prefix.len());
+                prefix.unwrap_or_default().to_owned()
+            }
+        };
+        *self.refused_keys.entry(blamed).or_default() += 1;
+        match &self.cardinality.admission {
+            Admission::Deny => Err(PushError::Cardinality),
+            Admission::Overflow(overflow) => Ok(Cow::Owned(overflow.clone())),
+        }
+    }
+    fn count_prefix_keys(&mut self, key: &str, delta: isize) {
+        let prefixes = self.cardinality.prefixes.iter();
+        for ((prefix, _), count) in prefixes.zip(&mut self.prefix_key_counts) {
+            if key.starts_with(prefix.as_str()) {
+                *count = count.checked_add_signed(delta).unwrap();
+            }
+        }
+    }
+
+    pub fn queue_config(&self, key: &str) -> MetricQueueConfig {
+        queue_config(&self.default_config, &self.configs, key).clone()
+    }
+    pub fn default_queue_config(&self) -> &MetricQueueConfig {
+        &self.default_config
+    }
+    /// Applies to queues created afterwards that match no pattern
+    pub fn set_default_queue_config(&mut self, config: MetricQueueConfig) {
+        self.default_config = config;
+    }
+    pub fn queue_configs(&self) -> &[(KeyPattern, MetricQueueConfig)] {
+        &self.configs
+    }
+    /// Replaces all the configs set by [`MetricConsumer::set_queue_config`], including for the existing queues
+    pub fn set_queue_configs(&mut self, configs: Vec<(KeyPattern, MetricQueueConfig)>) {
+        self.configs = configs;
+        for (key, queue) in &mut self.metrics {
+            let config = queue_config(&self.default_config, &self.configs, key);
+            if queue.config() != config {
+                Arc::make_mut(queue).set_config(config.clone());
+            }
+        }
+    }
+    /// Overrides configs set earlier for the matching keys, including the existing queues
+    pub fn set_queue_config(&mut self, pattern: KeyPattern, config: MetricQueueConfig) {
+        for (key, queue) in &mut self.metrics {
+            if pattern.matches(key) && *queue.config() != config {
+                Arc::make_mut(queue).set_config(config.clone());
+            }
+        }
+        self.configs.push((pattern, config));
+    }
+
+    /// Converts a range of durations since the Unix epoch to a range of [`Time`]
+    pub fn time_range(&self, range: impl core::ops::RangeBounds<Duration>) -> RangeAny<Time> {
+        let convert = |bound: Bound<&Duration>| bound.map(|d| self.time_unit.from_duration(*d));
+        RangeAny::from_range((convert(range.start_bound()), convert(range.end_bound())))
+    }
+    pub fn span(
+        &self,
+        key: &str,
+        range: impl core::ops::RangeBounds<Duration>,
+    ) -> Option<TimeSeriesSpan<'_>> {
+        let queue = self.metrics.get(key)?;
+        TimeSeries::span(&**queue, self.time_range(range))
+    }
+}
+
+/// Orders queues by the time of their oldest sample or not at all, and then by their last write
+///
+/// Empty queues come first. [`None`] without a budget.
+fn eviction_rank(budget: Option<MemoryBudget>, queue: &MetricQueue) -> Option<(Time, u64)> {
+    let time = match budget?.eviction {
+        Eviction::OldestSamples => queue.buf.front().map(|sample| sample.time).unwrap_or(0),
+        Eviction::LeastRecentlyWritten => 0,
+    };
+    Some((time, queue.last_write))
+}
+fn queue_bytes(key: &MetricKey, queue: &MetricQueue) -> usize {
+    core::mem::size_of::<MetricKey>() + key.len() + queue.bytes()
+}
+
+/// A queue removed for receiving no samples for longer than its [`MetricQueueConfig::idle_ttl`]
+///
+/// Tells a series that is gone apart from one that merely reports zeros.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeriesExpired {
+    pub key: MetricKey,
+    /// Clock time of the last push
+    pub last_write_time: Time,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBudget {
+    /// Approximate bytes all queues may hold together
+    pub bytes: usize,
+    pub eviction: Eviction,
+}
+/// What goes first once the memory budget is exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    /// The oldest samples across all queues; queues are removed once empty
+    OldestSamples,
+    /// Whole queues, least recently written first
+    LeastRecentlyWritten,
+}
+/// Counts what the memory budget has evicted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EvictionStats {
+    pub samples: u64,
+    pub keys: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CardinalityLimits {
+    /// Maximum number of keys overall
+    pub max_keys: Option<usize>,
+    /// Maximum number of keys starting with each prefix
+    pub prefixes: Vec<(String, usize)>,
+    pub admission: Admission,
+}
+/// What happens to the samples of a new key over the limits
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Admission {
+    /// Refuse them with [`PushError::Cardinality`]
+    #[default]
+    Deny,
+    /// Redirect them to this key, which is exempt from the limits
+    Overflow(MetricKey),
+}
+
+/// [`None`] if `range` is empty
+fn inclusive_range(range: impl core::ops::RangeBounds<Time>) -> Option<RangeInclusive<Time>> {
+    let start = match range.start_bound() {
+        Bound::Included(&start) => start,
+        Bound::Excluded(&start) => start.checked_add(1)?,
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&end) => end,
+        Bound::Excluded(&end) => end.checked_sub(1)?,
+        Bound::Unbounded => Time::MAX,
+    };
+    (start <= end).then_some(start..=end)
+}
+fn queue_config<'a>(
+    default_config: &'a MetricQueueConfig,
+    configs: &'a [(KeyPattern, MetricQueueConfig)],
+    key: &str,
+) -> &'a MetricQueueConfig {
+    configs
+        .iter()
+        .rev()
+        .find(|(pattern, _)| pattern.matches(key))
+        .map(|(_, config)| config)
+        .unwrap_or(default_config)
+}
+fn retention_cutoff(
+    clock: &dyn Clock,
+    time_unit: TimeUnit,
+    config: &MetricQueueConfig,
+) -> Option<Time> {
+    let max_age = time_unit.from_duration(config.max_age?);
+    Some(clock.timestamp(time_unit).saturating_sub(max_age))
+}
+
+/// Keys of the queues matching `pattern` in order, scanning only those starting with its [`KeyPattern::literal_prefix`]
+pub fn matching_keys<'a: 'p, 'p>(
+    metrics: &'a MetricQueues,
+    pattern: &'p KeyPattern,
+) -> impl Iterator<Item = &'a MetricKey> + 'p {
+    let prefix = pattern.literal_prefix();
+    metrics
+        .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+        .map(|(key, _)| key)
+        .take_while(move |key| key.starts_with(prefix))
+        .filter(|key| pattern.matches(key))
+}
+
+#[derive(Debug, Clone)]
+pub enum KeyPattern {
+    Exact(MetricKey),
+    Prefix(String),
+    /// `*` matches any run of characters and `?` any single one, unless escaped by `\`
+    Glob(String),
+    Regex(regex::Regex),
+}
+impl KeyPattern {
+    /// `/re/` is a regex, text with `*` or `?` a glob and anything else an exact key
+    ///
+    /// `\*`, `\?` and `\\` stand for the literal characters, so `a\*` is the exact key `a*`.
+    pub fn parse(s: &str) -> Result<Self, regex::Error> {
+        if let Some(re) = s.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            return Ok(Self::Regex(regex::Regex::new(re)?));
+        }
+        let tokens = glob_tokens(s);
+        if tokens
+            .iter()
+            .any(|token| !matches!(token, GlobToken::Char(_)))
+        {
+            return Ok(Self::Glob(s.into()));
+        }
+        let exact = tokens.into_iter().map(|token| match token {
+            GlobToken::Char(c) => c,
+            GlobToken::Star | GlobToken::One => unreachable!(),
+        });
+        Ok(Self::Exact(exact.collect()))
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyPattern::Exact(exact) => key == exact,
+            KeyPattern::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            KeyPattern::Glob(glob) => glob_matches(glob, key),
+            KeyPattern::Regex(re) => re.is_match(key),
+        }
+    }
+    /// All matching keys start with it
+    pub fn literal_prefix(&self) -> &str {
+        match self {
+            KeyPattern::Exact(exact) => exact,
+            KeyPattern::Prefix(prefix) => prefix,
+            KeyPattern::Glob(glob) => {
+                let end = glob.find(['*', '?', '\\']).unwrap_or(glob.len());
+                &glob[..end]
+            }
+            KeyPattern::Regex(_) => "",
+        }
+    }
+}
+impl PartialEq for KeyPattern {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Exact(a), Self::Exact(b)) => a == b,
+            (Self::Prefix(a), Self::Prefix(b)) => a == b,
+            (Self::Glob(a), Self::Glob(b)) => a == b,
+            (Self::Regex(a), Self::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+impl Eq for KeyPattern {}
+
+enum GlobToken {
+    /// `*`
+    Star,
+    /// `?`
+    One,
+    Char(char),
+}
+/// A `\` escapes the `*`, `?` or `\` after it and is taken literally before anything else
+fn glob_tokens(glob: &str) -> Vec<GlobToken> {
+    let mut tokens = vec![];
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '*' => GlobToken::Star,
+            '?' => GlobToken::One,
+            '\\' => match chars.next_if(|c| matches!(c, '*' | '?' | '\\')) {
+                Some(escaped) => GlobToken::Char(escaped),
+                None => GlobToken::Char(c),
+            },
+            c => GlobToken::Char(c),
+        };
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn glob_matches(glob: &str, key: &str) -> bool {
+    let glob = glob_tokens(glob);
+    let key: Vec<char> = key.chars().collect();
+    let (mut g, mut k) = (0, 0);
+    // Position after the last `*` and the key position it was tried at
+    let mut backtrack = None;
+    while k < key.len() {
+        match glob.get(g) {
+            Some(GlobToken::Star) => {
+                g += 1;
+                backtrack = Some((g, k));
+            }
+            Some(GlobToken::One) => {
+                g += 1;
+                k += 1;
+            }
+            Some(&GlobToken::Char(c)) if c == key[k] => {
+                g += 1;
+                k += 1;
+            }
+            _ => match backtrack {
+                // Let the `*` take one more character
+                Some((star_g, star_k)) => {
+                    g = star_g;
+                    k = star_k + 1;
+                    backtrack = Some((star_g, star_k + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..]
+        .iter()
+        .all(|token| matches!(token, GlobToken::Star))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricQueueConfig {
+    pub late_policy: LatePolicy,
+    /// Samples older than this are evicted on push and on [`MetricConsumer::sweep`], on top of the count cap
+    pub max_age: Option<Duration>,
+    /// Coarser summaries populated on push and kept independently of the raw samples
+    pub rollups: Vec<RollupTier>,
+    /// The queue is removed on [`MetricConsumer::sweep`] once nothing has been pushed to it for this long
+    pub idle_ttl: Option<Duration>,
+    /// The storage and the write-ahead log still keep every sample as pushed
+    pub duplicate_policy: DuplicatePolicy,
+}
+
+/// What to do with a sample at the same time as one already in the queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Store both
+    #[default]
+    Keep,
+    KeepFirst,
+    KeepLast,
+    Sum,
+    Max,
+}
+
+/// What to do with a sample older than the newest one in the queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LatePolicy {
+    /// Append it to the back anyway, leaving the queue out of order
+    #[default]
+    Append,
+    /// Insert it in time order if it is late by no more than `tolerance`; drop it otherwise or if it would be the oldest of a full queue
+    Insert {
+        tolerance: Time,
+    },
+    Drop,
+    /// Drop it and report [`PushError::Late`]
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushError {
+    Late,
+    Cardinality,
+    Wal(io::ErrorKind),
+}
+impl core::fmt::Display for PushError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PushError::Late => write!(f, "sample is older than the newest one"),
+            PushError::Cardinality => write!(f, "key limit reached"),
+            PushError::Wal(kind) => write!(f, "failed to journal the sample: {kind}"),
+        }
+    }
+}
+impl std::error::Error for PushError {}
+
+/// What [`MetricQueue::push`] did with a sample
+#[derive(Debug, Clone, Copy)]
+pub enum Pushed {
+    Stored,
+    /// Merged into the sample of the same time, leaving this one
+    Replaced(Sample),
+    /// Left out as late, or as a duplicate that changes nothing
+    Dropped,
 }
 
+/// Samples are kept in time order unless pushed out of order under [`LatePolicy::Append`].
+/// While any out-of-order pair is still in the queue, [`MetricQueue::span`] falls back to a linear scan.
 #[derive(Debug, Clone)]
 pub struct MetricQueue {
-    buf: VecDeque<Sample>,
+    /// Shares its chunks with the clones published to readers
+    buf: ChunkedDeque<Sample>,
+    config: MetricQueueConfig,
+    /// Number of samples ever popped from the front
+    popped: u64,
+    /// Latest time of the samples popped from the front, after which the raw samples miss nothing the rollups have
+    evicted_until: Option<Time>,
+    /// Absolute index of the latest sample that is earlier than its predecessor
+    unsorted_until: u64,
+    dropped_late: u64,
+    duplicates: u64,
+    /// Value of [`MetricConsumer`]'s write counter at the last push
+    last_write: u64,
+    /// Clock time of the last push
+    last_write_time: Time,
+    /// From the finest to the coarsest
+    rollups: Vec<Rollup>,
 }
 impl MetricQueue {
     pub fn new() -> Self {
-        let buf = VecDeque::new();
-        Self { buf }
+        Self::with_config(MetricQueueConfig::default())
+    }
+    pub fn with_config(config: MetricQueueConfig) -> Self {
+        let buf = ChunkedDeque::new();
+        let rollups = rollups(&config);
+        Self {
+            buf,
+            config,
+            popped: 0,
+            evicted_until: None,
+            unsorted_until: 0,
+            dropped_late: 0,
+            duplicates: 0,
+            last_write: 0,
+            last_write_time: 0,
+            rollups,
+        }
     }
 
-    pub fn push(&mut self, sample: Sample, queue_size: usize) {
-        let queue_size = self.buf.capacity().max(queue_size);
-        if self.buf.len() == queue_size {
-            self.buf.pop_front();
+    pub fn config(&self) -> &MetricQueueConfig {
+        &self.config
+    }
+    /// Rollups are started over if their tiers change
+    pub fn set_config(&mut self, config: MetricQueueConfig) {
+        if config.rollups != self.config.rollups {
+            self.rollups = rollups(&config);
         }
-        self.buf.push_back(sample);
+        self.config = config;
+    }
+    pub fn rollups(&self) -> &[Rollup] {
+        &self.rollups
+    }
+    /// Latest time of the samples popped from the front
+    pub fn evicted_until(&self) -> Option<Time> {
+        self.evicted_until
+    }
+    /// Clock time of the last push, in the time unit of the [`MetricConsumer`]
+    pub fn last_write_time(&self) -> Time {
+        self.last_write_time
+    }
+    /// Number of late samples dropped or rejected
+    pub fn dropped_late(&self) -> u64 {
+        self.dropped_late
+    }
+    /// Number of samples merged into an existing one at the same time
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+    /// Approximate bytes held by the queue, counting stored samples rather than allocated capacity
+    pub fn bytes(&self) -> usize {
+        let rollups = self
+            .rollups
+            .iter()
+            .map(|rollup| rollup.bytes())
+            .sum::<usize>();
+        core::mem::size_of::<Self>() + self.buf.len() * SAMPLE_SIZE + rollups
     }
 
-    pub fn span(&self, range: impl core::ops::RangeBounds<Time>) -> (&[Sample], &[Sample]) {
-        let (a, b) = self.buf.as_slices();
-        let mut slices = [a, b];
-        for slice in &mut slices {
-            let start = range.start_bound();
-            if start != core::ops::Bound::Unbounded {
-                let pos = slice.iter().position(|sample| match start {
-                    std::ops::Bound::Included(&start) => start <= sample.time,
-                    std::ops::Bound::Excluded(&start) => start < sample.time,
-                    std::ops::Bound::Unbounded => unreachable!(),
+    pub fn push(&mut self, sample: Sample, queue_size: usize) -> Result<Pushed, PushError> {
+        if self.config.duplicate_policy != DuplicatePolicy::Keep {
+            if let Some(pos) = self.position(sample.time) {
+                return Ok(match self.merge_duplicate(pos, sample.value) {
+                    Some(merged) => Pushed::Replaced(merged),
+                    None => Pushed::Dropped,
                 });
-                *slice = match pos {
-                    Some(pos) => &slice[pos..],
-                    None => &[],
-                };
             }
-            let end = range.end_bound();
-            if end != core::ops::Bound::Unbounded {
-                let pos = slice.iter().rev().position(|sample| match end {
-                    std::ops::Bound::Included(&end) => sample.time <= end,
-                    std::ops::Bound::Excluded(&end) => sample.time < end,
-                    std::ops::Bound::Unbounded => unreachable!(),
-                });
-                *slice = match pos {
-                    Some(pos) => &slice[..slice.len() - pos],
-                    None => &[],
-                };
+        }
+        let late_by = self
+            .buf
+            .back()
+            .and_then(|back| back.time.checked_sub(sample.time))
+            .filter(|&late_by| late_by != 0);
+        match (late_by, self.config.late_policy) {
+            (None, _) => self.push_back(sample, queue_size),
+            (Some(_), LatePolicy::Append) => {
+                self.push_back(sample, queue_size);
+                self.unsorted_until = self.popped + self.buf.len() as u64 - 1;
+            }
+            (Some(late_by), LatePolicy::Insert { tolerance }) if late_by <= tolerance => {
+                let oldest = self
+                    .buf
+                    .front()
+                    .is_some_and(|front| sample.time < front.time);
+                if oldest && self.is_full(queue_size) {
+                    // It would be the one to make room for itself
+                    self.dropped_late += 1;
+                    return Ok(Pushed::Dropped);
+                }
+                self.pop_full(queue_size);
+                let pos = self.buf.partition_point(|other| other.time <= sample.time);
+                self.buf.insert(pos, sample);
             }
+            (Some(_), LatePolicy::Insert { .. } | LatePolicy::Drop) => {
+                self.dropped_late += 1;
+                return Ok(Pushed::Dropped);
+            }
+            (Some(_), LatePolicy::Reject) => {
+                self.dropped_late += 1;
+                return Err(PushError::Late);
+            }
+        }
+        for rollup in &mut self.rollups {
+            rollup.push(sample);
+        }
+        Ok(Pushed::Stored)
+    }
+    /// Rollup buckets overlapping `range` are rebuilt from the raw samples left
+    ///
+    /// Returns the number of raw samples deleted,
+    /// or [`None`] without deleting anything if `range` would cut through a rollup bucket that has samples the queue has evicted,
+    /// since a summary cannot be split.
+    pub fn delete(&mut self, range: RangeInclusive<Time>) -> Option<usize> {
+        if !self.can_delete(&range) {
+            return None;
+        }
+        let len = self.buf.len();
+        self.buf.retain(|sample| !range.contains(&sample.time));
+        // The indices have shifted
+        let times = self.buf.iter().map(|sample| sample.time);
+        let unsorted = times
+            .clone()
+            .zip(times.skip(1))
+            .enumerate()
+            .filter(|(_, (prev, time))| time < prev)
+            .last()
+            .map(|(i, _)| i + 1);
+        self.unsorted_until = self.popped + unsorted.unwrap_or(0) as u64;
+        self.rebuild_rollups(range);
+        Some(len - self.buf.len())
+    }
+    /// Whether [`MetricQueue::delete`] would take `range`
+    pub fn can_delete(&self, range: &RangeInclusive<Time>) -> bool {
+        let raw_since = self.raw_since();
+        !self
+            .rollups
+            .iter()
+            .any(|rollup| rollup.cuts(range, raw_since))
+    }
+    /// The raw samples are all there from this time on
+    fn raw_since(&self) -> Time {
+        match self.evicted_until {
+            Some(time) => time.saturating_add(1),
+            None => 0,
+        }
+    }
+    fn rebuild_rollups(&mut self, range: RangeInclusive<Time>) {
+        for rollup in &mut self.rollups {
+            let Some(window) = rollup.delete(range.clone()) else {
+                continue;
+            };
+            for sample in self
+                .buf
+                .iter()
+                .filter(|sample| window.contains(&sample.time))
+            {
+                rollup.push(*sample);
+            }
+        }
+    }
+    /// Pops samples from the front until one is no older than `cutoff`
+    ///
+    /// Returns the number of popped samples.
+    pub fn evict_before(&mut self, cutoff: Time) -> usize {
+        let mut evicted = 0;
+        while let Some(front) = self.buf.front() {
+            if cutoff <= front.time {
+                break;
+            }
+            self.pop_front();
+            evicted += 1;
+        }
+        evicted
+    }
+    /// Index of a sample at `time`
+    fn position(&self, time: Time) -> Option<usize> {
+        if !self.is_sorted() {
+            let pos = self
+                .buf
+                .iter()
+                .rev()
+                .position(|sample| sample.time == time)?;
+            return Some(self.buf.len() - 1 - pos);
+        }
+        let pos = self.buf.partition_point(|sample| sample.time < time);
+        let found = self.buf.get(pos)?.time == time;
+        found.then_some(pos)
+    }
+    /// Returns the sample with the merged value if that changed it
+    fn merge_duplicate(&mut self, pos: usize, value: f64) -> Option<Sample> {
+        self.duplicates += 1;
+        let existing = self.buf.get_mut(pos).unwrap();
+        let merged = match self.config.duplicate_policy {
+            DuplicatePolicy::Keep | DuplicatePolicy::KeepFirst => return None,
+            DuplicatePolicy::KeepLast => value,
+            DuplicatePolicy::Sum => existing.value + value,
+            DuplicatePolicy::Max => existing.value.max(value),
+        };
+        if merged.to_bits() == existing.value.to_bits() {
+            return None;
+        }
+        let (time, old) = (existing.time, existing.value);
+        existing.value = merged;
+        let replaced = *existing;
+        let raw_since = self.raw_since();
+        for i in 0..self.rollups.len() {
+            let Some(window) = self.rollups[i].replace(time, old, merged) else {
+                continue;
+            };
+            // The replaced extreme stays where some of the samples are evicted
+            if *window.start() < raw_since {
+                continue;
+            }
+            let values = self.span(window.clone()).iter().map(|sample| sample.value);
+            let (min, max) = values
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+                    (min.min(value), max.max(value))
+                });
+            self.rollups[i].set_extremes(*window.start(), min, max);
+        }
+        Some(replaced)
+    }
+    fn pop_front(&mut self) -> Option<Sample> {
+        let sample = self.buf.pop_front()?;
+        self.popped += 1;
+        self.evicted_until = self.evicted_until.max(Some(sample.time));
+        Some(sample)
+    }
+
+    fn push_back(&mut self, sample: Sample, queue_size: usize) {
+        self.pop_full(queue_size);
+        self.buf.push_back(sample);
+    }
+    /// Makes room for one more sample
+    fn pop_full(&mut self, queue_size: usize) {
+        if self.is_full(queue_size) {
+            self.pop_front();
+        }
+    }
+    fn is_full(&self, queue_size: usize) -> bool {
+        queue_size <= self.buf.len()
+    }
+
+    pub fn is_sorted(&self) -> bool {
+        self.unsorted_until <= self.popped
+    }
+
+    pub fn span(&self, range: impl core::ops::RangeBounds<Time>) -> ChunkedSpan<'_, Sample> {
+        if !self.is_sorted() {
+            return self.span_linear(range);
+        }
+        let start = match range.start_bound() {
+            Bound::Included(&start) => self.buf.partition_point(|sample| sample.time < start),
+            Bound::Excluded(&start) => self.buf.partition_point(|sample| sample.time <= start),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => self.buf.partition_point(|sample| sample.time <= end),
+            Bound::Excluded(&end) => self.buf.partition_point(|sample| sample.time < end),
+            Bound::Unbounded => self.buf.len(),
+        };
+        self.buf.span(start..end)
+    }
+    /// Trims samples outside of `range` from both ends without assuming any order
+    fn span_linear(&self, range: impl core::ops::RangeBounds<Time>) -> ChunkedSpan<'_, Sample> {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => self.buf.iter().position(|sample| start <= sample.time),
+            Bound::Excluded(&start) => self.buf.iter().position(|sample| start < sample.time),
+            Bound::Unbounded => Some(0),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => self.buf.iter().rev().position(|sample| sample.time <= end),
+            Bound::Excluded(&end) => self.buf.iter().rev().position(|sample| sample.time < end),
+            Bound::Unbounded => Some(0),
+        };
+        match (start, end) {
+            (Some(start), Some(end)) => self.buf.span(start..self.buf.len() - end),
+            _ => self.buf.span(0..0),
         }
-        (slices[0], slices[1])
     }
 }
+fn rollups(config: &MetricQueueConfig) -> Vec<Rollup> {
+    let mut tiers = config.rollups.clone();
+    tiers.sort_unstable_by_key(|tier| tier.step);
+    tiers.into_iter().map(Rollup::new).collect()
+}
 impl Default for MetricQueue {
     fn default() -> Self {
         Self::new()
@@ -88,19 +1383,385 @@ impl Default for MetricQueue {
 
 pub trait TimeSeries {
     fn span(&self, time_range: impl core::ops::RangeBounds<Time>) -> Option<TimeSeriesSpan<'_>>;
+    /// Might answer from a coarser representation whose samples are no more than `resolution` apart, unless it is zero
+    fn span_at(
+        &self,
+        time_range: impl core::ops::RangeBounds<Time>,
+        resolution: Time,
+    ) -> Option<TimeSeriesSpan<'_>> {
+        let _ = resolution;
+        self.span(time_range)
+    }
 }
 pub struct TimeSeriesSpan<'a> {
     pub samples: Box<dyn Iterator<Item = Sample> + Send + 'a>,
+    /// Might be more than the samples yielded by a span that skips some of them lazily
     pub count: usize,
 }
 impl TimeSeries for MetricQueue {
     fn span(&self, time_range: impl core::ops::RangeBounds<Time>) -> Option<TimeSeriesSpan<'_>> {
-        let (a, b) = self.span(time_range);
-        let n = a.len() + b.len();
-        let samples = a.iter().chain(b).copied();
+        let span = self.span(time_range);
+        let n = span.len();
+        let samples = span.iter().copied();
+        Some(TimeSeriesSpan {
+            samples: Box::new(samples),
+            count: n,
+        })
+    }
+    /// Picks the coarsest of the raw samples and the rollups that is within `resolution` and reaches back to the start of `time_range`.
+    /// Failing that, the finest that reaches back, and failing that, the raw samples.
+    /// A `resolution` of zero always picks the raw samples.
+    ///
+    /// A rollup bucket is represented by its start time and its last value.
+    fn span_at(
+        &self,
+        time_range: impl core::ops::RangeBounds<Time>,
+        resolution: Time,
+    ) -> Option<TimeSeriesSpan<'_>> {
+        if resolution == 0 {
+            return TimeSeries::span(self, time_range);
+        }
+        let reaches_back = |first: Option<Time>| match time_range.start_bound() {
+            Bound::Included(&start) | Bound::Excluded(&start) => {
+                first.is_some_and(|first| first <= start)
+            }
+            Bound::Unbounded => false,
+        };
+        let raw_reaches_back = reaches_back(self.buf.front().map(|sample| sample.time));
+        let coarsest = self
+            .rollups
+            .iter()
+            .rev()
+            .filter(|rollup| rollup.tier().step <= resolution)
+            .find(|rollup| reaches_back(rollup.first_time()));
+        let finest = || {
+            self.rollups
+                .iter()
+                .find(|rollup| reaches_back(rollup.first_time()))
+        };
+        let rollup = match coarsest {
+            Some(rollup) => rollup,
+            None if raw_reaches_back => return TimeSeries::span(self, time_range),
+            None => match finest() {
+                Some(rollup) => rollup,
+                None => return TimeSeries::span(self, time_range),
+            },
+        };
+        let span = rollup.span(time_range);
+        let n = span.len();
+        let samples = span.iter().map(|bucket| Sample {
+            time: bucket.start,
+            value: bucket.last,
+        });
         Some(TimeSeriesSpan {
             samples: Box::new(samples),
             count: n,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    fn sample(time: Time, value: f64) -> Sample {
+        Sample { time, value }
+    }
+    fn times(queue: &MetricQueue) -> Vec<Time> {
+        let span = queue.span(..);
+        span.iter().map(|sample| sample.time).collect()
+    }
+
+    #[test]
+    fn test_insert_late_into_full_queue() {
+        let mut queue = MetricQueue::with_config(MetricQueueConfig {
+            late_policy: LatePolicy::Insert {
+                tolerance: Time::MAX,
+            },
+            ..Default::default()
+        });
+        for time in [10, 20, 30, 40] {
+            queue.push(sample(time, 0.), 4).unwrap();
+        }
+        queue.push(sample(5, 0.), 4).unwrap();
+        assert_eq!(times(&queue), [10, 20, 30, 40]);
+        assert_eq!(queue.dropped_late(), 1);
+        queue.push(sample(15, 0.), 4).unwrap();
+        assert_eq!(times(&queue), [15, 20, 30, 40]);
+        assert_eq!(queue.dropped_late(), 1);
+    }
+
+    #[test]
+    fn test_evict_oldest_samples() {
+        let mut consumer = MetricConsumer::new(16);
+        let budget = |bytes| {
+            Some(MemoryBudget {
+                bytes,
+                eviction: Eviction::OldestSamples,
+            })
+        };
+        consumer.set_memory_budget(budget(usize::MAX));
+        for (key, times) in [("a", [10, 11, 12]), ("b", [0, 1, 2])] {
+            let key = key.to_string();
+            let mut push = consumer.push(&key);
+            for time in times {
+                push(sample(time, 0.)).unwrap();
+            }
+        }
+        consumer.set_memory_budget(budget(consumer.bytes() - 2 * SAMPLE_SIZE));
+        assert_eq!(times(&consumer.metrics()["b"]), [2]);
+        consumer.set_memory_budget(budget(consumer.bytes()));
+        consumer.push(&"a".into())(sample(13, 0.)).unwrap();
+        assert!(!consumer.metrics().contains_key("b"));
+        assert_eq!(times(&consumer.metrics()["a"]), [10, 11, 12, 13]);
+        assert_eq!(
+            consumer.eviction_stats(),
+            EvictionStats {
+                samples: 3,
+                keys: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_span_at_raw_without_resolution() {
+        let mut queue = MetricQueue::with_config(MetricQueueConfig {
+            rollups: vec![RollupTier {
+                step: 10,
+                capacity: 16,
+            }],
+            ..Default::default()
+        });
+        for time in 0..40 {
+            queue.push(sample(time, time as f64), 8).unwrap();
+        }
+        let span = TimeSeries::span_at(&queue, 0.., 0).unwrap();
+        let raw: Vec<Time> = span.samples.map(|sample| sample.time).collect();
+        assert_eq!(raw, (32..40).collect::<Vec<_>>());
+        let span = TimeSeries::span_at(&queue, 0.., 10).unwrap();
+        let buckets: Vec<Time> = span.samples.map(|sample| sample.time).collect();
+        assert_eq!(buckets, [0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn test_delete_rollup_buckets() {
+        let mut queue = MetricQueue::with_config(MetricQueueConfig {
+            rollups: vec![RollupTier {
+                step: 10,
+                capacity: 16,
+            }],
+            ..Default::default()
+        });
+        for time in 0..40 {
+            queue.push(sample(time, time as f64), 16).unwrap();
+        }
+        assert_eq!(times(&queue)[0], 24);
+        // Samples 20 to 23 of the bucket at 20 are evicted
+        assert_eq!(queue.delete(25..=26), None);
+        assert_eq!(queue.len(), 16);
+        assert_eq!(queue.delete(35..=36), Some(2));
+        let bucket = queue.rollups()[0].buckets()[3];
+        assert_eq!((bucket.start, bucket.count), (30, 8));
+        assert_eq!(bucket.sum, (30..40).sum::<Time>() as f64 - 35. - 36.);
+        // Whole buckets go regardless
+        assert_eq!(queue.delete(10..=19), Some(0));
+        let starts: Vec<Time> = queue.rollups()[0]
+            .buckets()
+            .iter()
+            .map(|bucket| bucket.start)
+            .collect();
+        assert_eq!(starts, [0, 20, 30]);
+    }
+
+    #[test]
+    fn test_merge_duplicate_rollups() {
+        let mut queue = MetricQueue::with_config(MetricQueueConfig {
+            rollups: vec![RollupTier {
+                step: 10,
+                capacity: 16,
+            }],
+            duplicate_policy: DuplicatePolicy::KeepLast,
+            ..Default::default()
+        });
+        for time in 0..20 {
+            queue.push(sample(time, time as f64), 8).unwrap();
+        }
+        // The bucket at 10 lost 10 and 11 from the raw samples
+        queue.push(sample(19, 0.), 8).unwrap();
+        let bucket = queue.rollups()[0].buckets()[1];
+        assert_eq!(bucket.count, 10);
+        assert_eq!(bucket.sum, (10..19).sum::<Time>() as f64);
+        assert_eq!((bucket.min, bucket.max, bucket.last), (0., 19., 0.));
+
+        queue.push(sample(15, 100.), 8).unwrap();
+        queue.push(sample(15, 15.), 8).unwrap();
+        let bucket = queue.rollups()[0].buckets()[1];
+        assert_eq!(bucket.max, 100.);
+        assert_eq!(queue.rollups()[0].buckets()[0].count, 10);
+
+        let mut queue = MetricQueue::with_config(queue.config().clone());
+        for time in 0..10 {
+            queue.push(sample(time, time as f64), 16).unwrap();
+        }
+        queue.push(sample(9, 0.), 16).unwrap();
+        queue.push(sample(0, 5.), 16).unwrap();
+        let bucket = queue.rollups()[0].buckets()[0];
+        assert_eq!((bucket.min, bucket.max), (0., 8.));
+    }
+
+    #[test]
+    fn test_load_storage_rollups() {
+        let dir = std::env::temp_dir().join(format!("metrics-load-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = || DiskStore::open(&dir, TimeUnit::Millis, Default::default()).unwrap();
+        let mut storage = open();
+        for time in 0..100 {
+            storage.push(&"a".into(), sample(time, 0.));
+        }
+        storage.flush().unwrap();
+        drop(storage);
+
+        let mut consumer = MetricConsumer::new(8);
+        consumer.set_default_queue_config(MetricQueueConfig {
+            rollups: vec![RollupTier {
+                step: 10,
+                capacity: 100,
+            }],
+            ..Default::default()
+        });
+        consumer.set_storage(Some(open()));
+        consumer.load_storage().unwrap();
+        let queue = &consumer.metrics()["a"];
+        assert_eq!(queue.rollups()[0].buckets().len(), 10);
+        assert_eq!(times(queue).last(), Some(&99));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_kept_samples() {
+        let dir = std::env::temp_dir().join(format!("metrics-kept-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = || DiskStore::open(&dir, TimeUnit::Millis, Default::default()).unwrap();
+        let mut consumer = MetricConsumer::new(8);
+        consumer.set_default_queue_config(MetricQueueConfig {
+            late_policy: LatePolicy::Drop,
+            duplicate_policy: DuplicatePolicy::Sum,
+            ..Default::default()
+        });
+        consumer.set_storage(Some(open()));
+        let key: MetricKey = "a".into();
+        for (time, value) in [(1, 1.), (2, 1.), (2, 2.), (0, 1.)] {
+            consumer.push(&key)(sample(time, value)).unwrap();
+        }
+        consumer.flush_storage().unwrap();
+        consumer.push(&key)(sample(2, 4.)).unwrap();
+        consumer.flush_storage().unwrap();
+        drop(consumer);
+
+        let samples = open().series("a").unwrap().read(..).unwrap();
+        let samples: Vec<(Time, f64)> = samples.iter().map(|s| (s.time, s.value)).collect();
+        assert_eq!(samples, [(1, 1.), (2, 7.)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_key_pattern() {
+        let glob = KeyPattern::parse("cpu.*.idle").unwrap();
+        assert!(glob.matches("cpu.0.idle"));
+        assert!(glob.matches("cpu..idle"));
+        assert!(!glob.matches("cpu.0.user"));
+        assert_eq!(glob.literal_prefix(), "cpu.");
+        let one = KeyPattern::parse("cpu.?").unwrap();
+        assert!(one.matches("cpu.1"));
+        assert!(!one.matches("cpu.10"));
+
+        let exact = KeyPattern::parse(r"load\*\?").unwrap();
+        assert_eq!(exact, KeyPattern::Exact("load*?".into()));
+        let escaped = KeyPattern::parse(r"a\**").unwrap();
+        assert!(escaped.matches("a*b"));
+        assert!(!escaped.matches("ab"));
+        assert_eq!(escaped.literal_prefix(), "a");
+        assert_eq!(
+            KeyPattern::parse(r"a\b").unwrap(),
+            KeyPattern::Exact(r"a\b".into())
+        );
+    }
+
+    #[test]
+    fn test_keys_in_order() {
+        let mut consumer = MetricConsumer::new(4);
+        for key in ["b.2", "a.1", "b.1", "c"] {
+            consumer.push(&key.to_string())(sample(1, 1.)).unwrap();
+        }
+        let pattern = KeyPattern::parse("b.*").unwrap();
+        let keys: Vec<&MetricKey> = consumer.keys(&pattern).collect();
+        assert_eq!(keys, ["b.1", "b.2"]);
+    }
+
+    #[test]
+    fn test_sweep_max_age() {
+        let clock = MockClock::new(Duration::from_secs(100));
+        let mut consumer = MetricConsumer::with_time_unit(16, TimeUnit::Seconds);
+        consumer.set_clock(Arc::new(clock.clone()));
+        consumer.set_default_queue_config(MetricQueueConfig {
+            max_age: Some(Duration::from_secs(10)),
+            ..Default::default()
+        });
+        let key: MetricKey = "a".into();
+        for time in [85, 95, 100] {
+            let _ = consumer.push(&key)(sample(time, 0.));
+        }
+        assert_eq!(times(&consumer.metrics()["a"]), [95, 100]);
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(consumer.sweep(), 1);
+        assert_eq!(times(&consumer.metrics()["a"]), [100]);
+        assert_eq!(consumer.sweep(), 0);
+    }
+
+    #[test]
+    fn test_idle_ttl() {
+        let clock = MockClock::new(Duration::from_secs(100));
+        let mut consumer = MetricConsumer::with_time_unit(16, TimeUnit::Seconds);
+        consumer.set_clock(Arc::new(clock.clone()));
+        consumer.set_default_queue_config(MetricQueueConfig {
+            idle_ttl: Some(Duration::from_secs(10)),
+            ..Default::default()
+        });
+        let mut expired = consumer.subscribe_expiry();
+        let (a, b): (MetricKey, MetricKey) = ("a".into(), "b".into());
+        consumer.push(&a)(sample(1, 0.)).unwrap();
+        clock.advance(Duration::from_secs(5));
+        consumer.push(&b)(sample(1, 0.)).unwrap();
+        clock.advance(Duration::from_secs(10));
+        consumer.sweep();
+        assert!(!consumer.metrics().contains_key("a"));
+        assert!(consumer.metrics().contains_key("b"));
+        let event = expired.try_recv().unwrap();
+        assert_eq!((event.key.as_str(), event.last_write_time), ("a", 100));
+        assert!(expired.try_recv().is_err());
+        // Pushing again brings it back
+        consumer.push(&a)(sample(2, 0.)).unwrap();
+        clock.advance(Duration::from_secs(1));
+        consumer.sweep();
+        assert!(consumer.metrics().contains_key("a"));
+        assert!(!consumer.metrics().contains_key("b"));
+    }
+
+    #[test]
+    fn test_refused_prefixes() {
+        let mut consumer = MetricConsumer::new(16);
+        consumer.set_cardinality_limits(CardinalityLimits {
+            max_keys: Some(2),
+            prefixes: vec![("req.".into(), 1)],
+            admission: Admission::Deny,
+        });
+        for key in ["req.1", "req.2", "a", "b", "c", "d"] {
+            let _ = consumer.push(&key.into())(sample(0, 0.));
+        }
+        assert_eq!(consumer.top_refused_prefixes(3), [("", 3), ("req.", 1)]);
+        consumer.set_cardinality_limits(CardinalityLimits::default());
+        assert_eq!(consumer.top_refused_prefixes(3), [("", 3)]);
+    }
+}
@@ -3,11 +3,12 @@ use std::io::{self, Write};
 use crate::{
     buf::{MetricBufReader, MetricBufReaders, BUF_SIZE},
     codec::{
-        decode_key, decode_sample, decode_sample_count, encode_key, encode_sample,
-        encode_sample_count,
+        decode_frame_version, decode_key, decode_sample, decode_sample_count, decode_time_unit,
+        encode_frame_version, encode_key, encode_sample, encode_sample_count, encode_time_unit,
+        read_checked_frame,
     },
-    consumer::MetricConsumer,
-    MetricKey, SAMPLE_SIZE,
+    consumer::{MetricConsumer, PushError},
+    MetricKey, Sample, TimeUnit, SAMPLE_SIZE,
 };
 
 #[derive(Debug)]
@@ -28,10 +29,11 @@ impl HttpExporter {
     }
     /// Blocking I/O
     pub fn export(&mut self) -> anyhow::Result<()> {
+        let time_unit = self.readers.time_unit();
         for (key, reader) in self.readers.readers_mut() {
             self.buf.clear();
             let mut wtr = io::Cursor::new(&mut self.buf);
-            if !encode_frame(key, reader, &mut wtr) {
+            if !encode_frame(key, reader, time_unit, &mut wtr) {
                 continue;
             }
             let _resp = self.client.post(&self.url).send_bytes(&self.buf)?;
@@ -43,9 +45,12 @@ impl HttpExporter {
 pub fn encode_frame(
     key: &MetricKey,
     metric_buf: &mut MetricBufReader,
+    time_unit: TimeUnit,
     wtr: &mut io::Cursor<&mut Vec<u8>>,
 ) -> bool {
+    wtr.write_all(&encode_frame_version()).unwrap();
     encode_key(wtr, key);
+    wtr.write_all(&encode_time_unit(time_unit)).unwrap();
     let sample_count_pos = wtr.position();
     let mut sample_count: u16 = 0;
     wtr.write_all(&encode_sample_count(sample_count)).unwrap();
@@ -66,26 +71,94 @@ pub fn encode_frame(
     wtr.set_position(curr_pos);
     true
 }
+/// Why [`decode_frame_copy`] failed
+#[derive(Debug)]
+pub enum FrameError {
+    /// The frame is malformed or cut short, after which the rest of the stream cannot be framed
+    Io(io::Error),
+    /// The frame is read in full, but the consumer refused some of its samples
+    Rejected(PushError),
+}
+impl core::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "malformed frame: {e}"),
+            FrameError::Rejected(e) => write!(f, "rejected samples: {e}"),
+        }
+    }
+}
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Io(e) => Some(e),
+            FrameError::Rejected(e) => Some(e),
+        }
+    }
+}
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Io(e) => e,
+            FrameError::Rejected(e) => io::Error::other(e),
+        }
+    }
+}
+
 pub async fn decode_frame_copy<R>(
     rdr: &mut R,
     consumer: &mut MetricConsumer,
     key_buf: &mut String,
-) -> io::Result<()>
+) -> Result<(), FrameError>
 where
     R: tokio::io::AsyncRead + Unpin,
 {
     use tokio::io::AsyncReadExt;
+    let mut version = [0; 1];
+    rdr.read_exact(&mut version).await?;
+    decode_frame_version(version)?;
     key_buf.clear();
     decode_key(rdr, key_buf).await?;
+    let mut time_unit = [0; 1];
+    rdr.read_exact(&mut time_unit).await?;
+    // The sample count comes after it, so the frame could not be skipped
+    let time_unit =
+        decode_time_unit(time_unit).map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e))?;
+    let consumer_time_unit = consumer.time_unit();
     let mut sample_count = [0; 2];
     rdr.read_exact(&mut sample_count).await?;
     let sample_count = decode_sample_count(sample_count);
     let mut queue = consumer.push(key_buf);
+    let mut res = Ok(());
     for _ in 0..sample_count {
         let mut sample = [0; SAMPLE_SIZE];
         rdr.read_exact(&mut sample).await?;
         let sample = decode_sample(sample);
-        queue(sample);
+        let time = time_unit.convert(sample.time, consumer_time_unit);
+        if let Err(e) = queue(Sample { time, ..sample }) {
+            // Keep reading so that the next frame stays aligned
+            res = Err(FrameError::Rejected(e));
+        }
+    }
+    res
+}
+/// Pushes the samples of all the frames written by [`crate::codec::encode_checked_frames`]
+///
+/// Rejected samples are left out, but a malformed or corrupt frame stops the copy.
+pub async fn copy_checked_frames<R>(rdr: &mut R, consumer: &mut MetricConsumer) -> io::Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let (mut frame, mut key) = (vec![], String::new());
+    while read_checked_frame(rdr, &mut frame).await? {
+        match decode_frame_copy(&mut &frame[..], consumer, &mut key).await {
+            Ok(()) | Err(FrameError::Rejected(_)) => (),
+            Err(FrameError::Io(e)) => return Err(e),
+        }
     }
     Ok(())
 }
@@ -100,13 +173,17 @@ impl InProcessExporter {
     }
 
     pub async fn flush(&mut self, consumer: &mut MetricConsumer) {
+        let time_unit = self.readers.time_unit();
+        let consumer_time_unit = consumer.time_unit();
         for (key, reader) in self.readers.readers_mut() {
             let mut queue = consumer.push(key);
             for _ in 0..BUF_SIZE {
                 let Some(sample) = reader.pop() else {
                     break;
                 };
-                queue(sample);
+                let time = time_unit.convert(sample.time, consumer_time_unit);
+                // Rejected samples are counted by the queue
+                let _ = queue(Sample { time, ..sample });
             }
             tokio::task::yield_now().await;
         }
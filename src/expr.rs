@@ -0,0 +1,943 @@
+//! Expressions and queries over series
+//!
+//! ```text
+//! expr      = term (("+" | "-") term)*
+//! term      = unary (("*" | "/") unary)*
+//! unary     = "-" unary | primary
+//! primary   = number | selector | call | aggregate | "(" expr ")"
+//! selector  = ident | '"' any '"'
+//! call      = ident "(" expr range? ")"
+//! range     = "[" number ("ms" | "s" | "m" | "h" | "d") "]"
+//! aggregate = ("sum" | "avg" | "min" | "max" | "count") ("by" "(" group ("," group)* ")")? "(" expr ")"
+//! group     = ident | number
+//! ```
+//!
+//! For example, `100 * mem.used / mem.total`, `rate(net.bytes[1m])` or `avg by (region) ("*.cpu")`.
+//!
+//! A quoted selector is parsed by [`KeyPattern::parse`] and picks all the matching series.
+//! A group is a label or the index of a key segment; see [`GroupBy`].
+//!
+//! Functions:
+//! - `abs(x)`
+//! - `rate(x[window])`: [`RateKind::Rate`]
+//! - `irate(x)`: [`RateKind::Irate`]
+//! - `deriv(x)`: [`RateKind::Derivative`]
+//! - `avg_over_time(x[window])`, `min_over_time`, `max_over_time`, `stddev_over_time`: [`crate::window::Rolling`]
+//!
+//! Between two sets of series, a single series is paired with each of the other set, and otherwise series pair up by name.
+
+use std::time::Duration;
+
+use primitive::ops::range::RangeAny;
+
+use crate::{
+    consumer::{KeyPattern, MetricQueues, TimeSeriesSpan},
+    group::{aggregate, GroupAggregation, GroupBy},
+    synthesis::{owned_span, rates, Alignment, BinaryOp, RateKind, Reach, SortedSamples},
+    view::{select_keys, MetricSources, MetricSyntheses, MetricSynthesis, SynthesisError},
+    window::{rolling, Aggregation},
+    MetricKey, Sample, Time, TimeUnit,
+};
+
+/// A parsed expression
+///
+/// Binary operations between two series are evaluated at the timestamps of the left one.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    expr: Expr,
+    time_unit: TimeUnit,
+    alignment: Alignment,
+    step: Time,
+}
+impl Expression {
+    /// `time_unit` is the one of the queues it reads
+    pub fn parse(s: &str, time_unit: TimeUnit) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            len: s.len(),
+            time_unit,
+        };
+        let expr = parser.expr()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(ParseError::new(token.offset, "unexpected token"));
+        }
+        Ok(Self {
+            expr,
+            time_unit,
+            alignment: Alignment::default(),
+            step: 0,
+        })
+    }
+    pub fn with_alignment(self, alignment: Alignment) -> Self {
+        Self { alignment, ..self }
+    }
+    /// Aggregations bucket samples at `step`, or at each timestamp if zero
+    pub fn with_step(self, step: Time) -> Self {
+        Self { step, ..self }
+    }
+
+    /// Whether it is nothing but a key
+    pub fn is_key(&self) -> bool {
+        matches!(self.expr, Expr::Selector(KeyPattern::Exact(_)))
+    }
+    /// All the selectors it reads
+    pub fn selectors(&self) -> Vec<&KeyPattern> {
+        let mut selectors = vec![];
+        self.expr.selectors(&mut selectors);
+        selectors
+    }
+    pub fn time_unit(&self) -> TimeUnit {
+        self.time_unit
+    }
+
+    /// Resolves the selectors to the keys currently in `sources`
+    ///
+    /// Patterns skip the syntheses being evaluated, so a synthesis can aggregate the series next to its own key.
+    pub fn plan(&self, sources: &MetricSources<'_>) -> Plan<'_> {
+        Plan {
+            expression: self,
+            node: Node::new(&self.expr, sources),
+        }
+    }
+    /// Named series it evaluates to; see [`Plan::eval`]
+    pub fn series(
+        &self,
+        metrics: &MetricQueues,
+        syntheses: &MetricSyntheses,
+        time_range: impl core::ops::RangeBounds<Time>,
+    ) -> Result<Vec<(String, TimeSeriesSpan<'static>)>, SynthesisError> {
+        let sources = MetricSources::new(metrics, syntheses);
+        self.plan(&sources).eval(&sources, time_range)
+    }
+}
+impl MetricSynthesis for Expression {
+    /// [`SynthesisError::SeriesCount`] unless it evaluates to exactly one series
+    fn span<'a>(
+        &'a self,
+        sources: &MetricSources<'a>,
+        time_range: RangeAny<Time>,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        let plan = self.plan(sources);
+        let mut series = plan.eval(sources, time_range)?;
+        match (series.pop(), series.len()) {
+            (Some((_, span)), 0) => Ok(span),
+            (None, _) => Err(SynthesisError::SeriesCount(0)),
+            (Some(_), n) => Err(SynthesisError::SeriesCount(n + 1)),
+        }
+    }
+}
+
+/// An [`Expression`] with its selectors resolved to keys
+#[derive(Debug, Clone)]
+pub struct Plan<'a> {
+    expression: &'a Expression,
+    node: Node,
+}
+impl Plan<'_> {
+    /// Keys of all the series it reads
+    pub fn keys(&self) -> Vec<&MetricKey> {
+        let mut keys = vec![];
+        self.node.keys(&mut keys);
+        keys
+    }
+
+    /// A series is named by its key, or by its group after an aggregation, e.g. `region=eu`
+    ///
+    /// Nothing if it evaluates to a constant.
+    pub fn eval(
+        &self,
+        sources: &MetricSources<'_>,
+        time_range: impl core::ops::RangeBounds<Time>,
+    ) -> Result<Vec<(String, TimeSeriesSpan<'static>)>, SynthesisError> {
+        let range = (
+            time_range.start_bound().cloned(),
+            time_range.end_bound().cloned(),
+        );
+        let range = RangeAny::from_range(range);
+        let Value::Vector(series) =
+            self.eval_node(&self.node, sources, &range, Reach::default())?
+        else {
+            return Ok(vec![]);
+        };
+        let series = series.into_iter().map(|(name, mut samples)| {
+            samples.retain(|sample| time_range.contains(&sample.time));
+            (name, owned_span(samples))
+        });
+        Ok(series.collect())
+    }
+
+    /// `reach` is how far out of `time_range` the outputs of `node` are needed
+    fn eval_node(
+        &self,
+        node: &Node,
+        sources: &MetricSources<'_>,
+        time_range: &RangeAny<Time>,
+        reach: Reach,
+    ) -> Result<Value, SynthesisError> {
+        Ok(match node {
+            Node::Number(n) => Value::Scalar(*n),
+            Node::Series(keys) => {
+                // Samples out of the range still count for alignment and windows
+                let series = keys.iter().map(|key| {
+                    let samples = SortedSamples::read(sources, key, *time_range, reach)?;
+                    Ok((key.clone(), samples.into_vec()))
+                });
+                Value::Vector(series.collect::<Result<_, _>>()?)
+            }
+            Node::Neg(node) => self
+                .eval_node(node, sources, time_range, reach)?
+                .map(|value| -value),
+            Node::Binary(op, a, b) => {
+                // Samples of the right side are matched to the timestamps of the left one
+                let b_reach = reach.and(self.expression.alignment.reach());
+                let a = self.eval_node(a, sources, time_range, reach)?;
+                let b = self.eval_node(b, sources, time_range, b_reach)?;
+                match (a, b) {
+                    (Value::Scalar(a), Value::Scalar(b)) => Value::Scalar(op.apply(a, b)),
+                    (a @ Value::Vector(_), Value::Scalar(b)) => a.map(|a| op.apply(a, b)),
+                    (Value::Scalar(a), b @ Value::Vector(_)) => b.map(|b| op.apply(a, b)),
+                    (Value::Vector(a), Value::Vector(b)) => Value::Vector(self.combine(*op, a, b)),
+                }
+            }
+            Node::Call(function, node) => {
+                let value =
+                    self.eval_node(node, sources, time_range, reach.and(function.reach()))?;
+                function.eval(value, self.expression.time_unit)
+            }
+            Node::Aggregate(aggregation, by, node) => {
+                // The last bucket within the range ends after it
+                let step = self.expression.step;
+                let reach = reach.and(Reach::after(step.saturating_sub(1)));
+                let Value::Vector(series) = self.eval_node(node, sources, time_range, reach)?
+                else {
+                    return Ok(Value::Vector(vec![]));
+                };
+                let group = |key: &str| {
+                    let groups = by.iter().map(|by| {
+                        let group = by.group(key)?;
+                        Some(match by {
+                            GroupBy::Label(label) => format!("{label}={group}"),
+                            _ => group.to_owned(),
+                        })
+                    });
+                    Some(groups.collect::<Option<Vec<_>>>()?.join("."))
+                };
+                Value::Vector(aggregate(series.into_iter(), group, *aggregation, step))
+            }
+        })
+    }
+
+    fn combine(&self, op: BinaryOp, a: Vec<Series>, b: Vec<Series>) -> Vec<Series> {
+        let pairs: Vec<(&Series, &Series, &String)> = match (a.len(), b.len()) {
+            (_, 1) => a.iter().map(|a| (a, &b[0], &a.0)).collect(),
+            (1, _) => b.iter().map(|b| (&a[0], b, &b.0)).collect(),
+            _ => a
+                .iter()
+                .filter_map(|a| Some((a, b.iter().find(|b| b.0 == a.0)?, &a.0)))
+                .collect(),
+        };
+        let alignment = &self.expression.alignment;
+        pairs
+            .into_iter()
+            .map(|((_, a), (_, b), name)| {
+                let samples = a.iter().filter_map(|sample| {
+                    let b = alignment.value_at(b, sample.time)?;
+                    Some(Sample {
+                        time: sample.time,
+                        value: op.apply(sample.value, b),
+                    })
+                });
+                (name.clone(), samples.collect())
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f64),
+    Selector(KeyPattern),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Box<Expr>),
+    Aggregate(GroupAggregation, Vec<GroupBy>, Box<Expr>),
+}
+impl Expr {
+    fn selectors<'a>(&'a self, selectors: &mut Vec<&'a KeyPattern>) {
+        match self {
+            Expr::Number(_) => (),
+            Expr::Selector(pattern) => selectors.push(pattern),
+            Expr::Neg(expr) | Expr::Call(_, expr) | Expr::Aggregate(_, _, expr) => {
+                expr.selectors(selectors)
+            }
+            Expr::Binary(_, a, b) => {
+                a.selectors(selectors);
+                b.selectors(selectors);
+            }
+        }
+    }
+}
+
+/// [`Expr`] with the keys matching each selector
+#[derive(Debug, Clone)]
+enum Node {
+    Number(f64),
+    Series(Vec<MetricKey>),
+    Neg(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+    Call(Function, Box<Node>),
+    Aggregate(GroupAggregation, Vec<GroupBy>, Box<Node>),
+}
+impl Node {
+    fn new(expr: &Expr, sources: &MetricSources<'_>) -> Self {
+        let new = |expr: &Expr| Box::new(Node::new(expr, sources));
+        match expr {
+            Expr::Number(n) => Node::Number(*n),
+            Expr::Selector(pattern) => {
+                let keys = select_keys(sources.metrics(), sources.syntheses(), pattern);
+                let keys = keys.into_iter().filter(|key| {
+                    matches!(pattern, KeyPattern::Exact(_)) || !sources.is_evaluating(key)
+                });
+                Node::Series(keys.cloned().collect())
+            }
+            Expr::Neg(expr) => Node::Neg(new(expr)),
+            Expr::Binary(op, a, b) => Node::Binary(*op, new(a), new(b)),
+            Expr::Call(function, expr) => Node::Call(*function, new(expr)),
+            Expr::Aggregate(aggregation, by, expr) => {
+                Node::Aggregate(*aggregation, by.clone(), new(expr))
+            }
+        }
+    }
+    fn keys<'a>(&'a self, keys: &mut Vec<&'a MetricKey>) {
+        match self {
+            Node::Number(_) => (),
+            Node::Series(series) => keys.extend(series),
+            Node::Neg(node) | Node::Call(_, node) | Node::Aggregate(_, _, node) => node.keys(keys),
+            Node::Binary(_, a, b) => {
+                a.keys(keys);
+                b.keys(keys);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Abs,
+    Rate(RateKind),
+    OverTime(Aggregation, Time),
+}
+impl Function {
+    /// `window` is the range of the argument
+    fn from_name(name: &str, window: Option<Time>) -> Result<Self, &'static str> {
+        // Shorter than the time unit of the queues
+        const EMPTY_WINDOW: &str = "takes a range of at least one time unit";
+        let over_time = match name {
+            "avg_over_time" => Some(Aggregation::Mean),
+            "min_over_time" => Some(Aggregation::Min),
+            "max_over_time" => Some(Aggregation::Max),
+            "stddev_over_time" => Some(Aggregation::StdDev),
+            _ => None,
+        };
+        if let Some(aggregation) = over_time {
+            let window = window.ok_or("takes a range")?;
+            if window == 0 {
+                return Err(EMPTY_WINDOW);
+            }
+            return Ok(Self::OverTime(aggregation, window));
+        }
+        match (name, window) {
+            ("abs", None) => Ok(Self::Abs),
+            ("rate", Some(0)) => Err(EMPTY_WINDOW),
+            ("rate", Some(window)) => Ok(Self::Rate(RateKind::Rate { window })),
+            ("rate", None) => Err("takes a range"),
+            ("irate", None) => Ok(Self::Rate(RateKind::Irate)),
+            ("deriv", None) => Ok(Self::Rate(RateKind::Derivative)),
+            ("abs" | "irate" | "deriv", Some(_)) => Err("takes no range"),
+            _ => Err("is unknown"),
+        }
+    }
+    /// How far out of a range its input is needed for its outputs within it
+    fn reach(&self) -> Reach {
+        match self {
+            Function::Abs => Reach::default(),
+            Function::Rate(RateKind::Rate { window }) | Function::OverTime(_, window) => {
+                Reach::before(*window)
+            }
+            Function::Rate(RateKind::Irate | RateKind::Derivative) => Reach::neighbors(),
+        }
+    }
+    fn eval(&self, value: Value, time_unit: TimeUnit) -> Value {
+        let map_series = |series: Vec<Series>, f: &dyn Fn(&[Sample]) -> Vec<Sample>| {
+            let series = series
+                .into_iter()
+                .map(|(name, samples)| (name, f(&samples)));
+            Value::Vector(series.collect())
+        };
+        match (self, value) {
+            (Function::Abs, value) => value.map(f64::abs),
+            // A constant has no change over time
+            (Function::Rate(_), Value::Scalar(_)) => Value::Scalar(0.),
+            (Function::OverTime(..), value @ Value::Scalar(_)) => value,
+            (Function::Rate(kind), Value::Vector(series)) => {
+                map_series(series, &|samples| rates(samples, *kind, time_unit, None))
+            }
+            (Function::OverTime(aggregation, window), Value::Vector(series)) => {
+                map_series(series, &|samples| rolling(samples, *window, *aggregation))
+            }
+        }
+    }
+}
+
+/// Sorted by time
+type Series = (String, Vec<Sample>);
+enum Value {
+    Scalar(f64),
+    Vector(Vec<Series>),
+}
+impl Value {
+    fn map(self, f: impl Fn(f64) -> f64) -> Self {
+        match self {
+            Value::Scalar(value) => Value::Scalar(f(value)),
+            Value::Vector(mut series) => {
+                series
+                    .iter_mut()
+                    .flat_map(|(_, samples)| samples)
+                    .for_each(|sample| sample.value = f(sample.value));
+                Value::Vector(series)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset in the source
+    pub offset: usize,
+    pub message: String,
+}
+impl ParseError {
+    fn new(offset: usize, message: impl Into<String>) -> Self {
+        Self {
+            offset,
+            message: message.into(),
+        }
+    }
+}
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} at {}", self.message, self.offset)
+    }
+}
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Number(f64),
+    Ident(String),
+    Quoted(String),
+    Punct(char),
+}
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    offset: usize,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(offset, c)) = chars.peek() {
+        let kind = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '+' | '-' | '*' | '/' | '(' | ')' | '[' | ']' | ',' => {
+                chars.next();
+                TokenKind::Punct(c)
+            }
+            '"' => {
+                chars.next();
+                let end = s[offset + 1..]
+                    .find('"')
+                    .ok_or_else(|| ParseError::new(offset, "unterminated quote"))?;
+                let quoted = &s[offset + 1..offset + 1 + end];
+                while chars.next_if(|&(i, _)| i <= offset + 1 + end).is_some() {}
+                TokenKind::Quoted(quoted.into())
+            }
+            c if c.is_ascii_digit() => {
+                let mut end = offset;
+                while let Some((i, c)) = chars.next_if(|&(_, c)| c.is_ascii_digit() || c == '.') {
+                    end = i + c.len_utf8();
+                }
+                let number = s[offset..end]
+                    .parse()
+                    .map_err(|_| ParseError::new(offset, "invalid number"))?;
+                TokenKind::Number(number)
+            }
+            c if is_ident_start(c) => {
+                let mut end = offset;
+                while let Some((i, c)) = chars.next_if(|&(_, c)| is_ident_continue(c)) {
+                    end = i + c.len_utf8();
+                }
+                TokenKind::Ident(s[offset..end].into())
+            }
+            _ => return Err(ParseError::new(offset, "unexpected character")),
+        };
+        tokens.push(Token { kind, offset });
+    }
+    Ok(tokens)
+}
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+fn is_ident_continue(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | ':' | '=')
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Length of the source
+    len: usize,
+    time_unit: TimeUnit,
+}
+impl Parser {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|token| &token.kind)
+    }
+    /// Offset of the next token or the end of the source
+    fn offset(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some(token) => token.offset,
+            None => self.len,
+        }
+    }
+    fn eat(&mut self, punct: char) -> bool {
+        let eaten = self.peek() == Some(&TokenKind::Punct(punct));
+        if eaten {
+            self.pos += 1;
+        }
+        eaten
+    }
+    fn eat_ident(&mut self, ident: &str) -> bool {
+        let eaten = matches!(self.peek(), Some(TokenKind::Ident(next)) if next == ident);
+        if eaten {
+            self.pos += 1;
+        }
+        eaten
+    }
+    fn expect(&mut self, punct: char) -> Result<(), ParseError> {
+        match self.eat(punct) {
+            true => Ok(()),
+            false => Err(ParseError::new(
+                self.offset(),
+                format!("expected `{punct}`"),
+            )),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Punct('+')) => BinaryOp::Add,
+                Some(TokenKind::Punct('-')) => BinaryOp::Sub,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.term()?));
+        }
+    }
+    fn term(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Punct('*')) => BinaryOp::Mul,
+                Some(TokenKind::Punct('/')) => BinaryOp::Div,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let offset = self.offset();
+        let Some(kind) = self.peek().cloned() else {
+            return Err(ParseError::new(offset, "unexpected end"));
+        };
+        self.pos += 1;
+        match kind {
+            TokenKind::Number(n) => Ok(Expr::Number(n)),
+            TokenKind::Quoted(pattern) => {
+                let pattern = KeyPattern::parse(&pattern)
+                    .map_err(|e| ParseError::new(offset, e.to_string()))?;
+                Ok(Expr::Selector(pattern))
+            }
+            TokenKind::Ident(name) => {
+                if let Some(aggregation) = group_aggregation(&name) {
+                    if self.eat_ident("by") {
+                        let by = self.groups()?;
+                        return self.aggregate(aggregation, by);
+                    }
+                    if self.peek() == Some(&TokenKind::Punct('(')) {
+                        return self.aggregate(aggregation, vec![]);
+                    }
+                }
+                if !self.eat('(') {
+                    return Ok(Expr::Selector(KeyPattern::Exact(name)));
+                }
+                let expr = self.expr()?;
+                let window = match self.eat('[') {
+                    true => Some(self.range()?),
+                    false => None,
+                };
+                self.expect(')')?;
+                let function = Function::from_name(&name, window)
+                    .map_err(|message| ParseError::new(offset, format!("`{name}` {message}")))?;
+                Ok(Expr::Call(function, Box::new(expr)))
+            }
+            TokenKind::Punct('(') => {
+                let expr = self.expr()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            TokenKind::Punct(_) => Err(ParseError::new(offset, "unexpected token")),
+        }
+    }
+    /// The rest of `range` after "["
+    fn range(&mut self) -> Result<Time, ParseError> {
+        let offset = self.offset();
+        let Some(TokenKind::Number(n)) = self.peek().cloned() else {
+            return Err(ParseError::new(offset, "expected a duration"));
+        };
+        self.pos += 1;
+        let secs = match self.peek() {
+            Some(TokenKind::Ident(unit)) => match unit.as_str() {
+                "ms" => n / 1e3,
+                "s" => n,
+                "m" => n * 60.,
+                "h" => n * 60. * 60.,
+                "d" => n * 60. * 60. * 24.,
+                _ => return Err(ParseError::new(self.offset(), "unknown time unit")),
+            },
+            _ => return Err(ParseError::new(self.offset(), "expected a time unit")),
+        };
+        self.pos += 1;
+        self.expect(']')?;
+        if !(secs.is_finite() && 0. < secs) {
+            return Err(ParseError::new(offset, "expected a positive duration"));
+        }
+        let duration = Duration::try_from_secs_f64(secs)
+            .map_err(|_| ParseError::new(offset, "duration out of range"))?;
+        Ok(self.time_unit.from_duration(duration))
+    }
+    /// The rest of `aggregate` after "by"
+    fn groups(&mut self) -> Result<Vec<GroupBy>, ParseError> {
+        self.expect('(')?;
+        let mut groups = vec![];
+        loop {
+            let offset = self.offset();
+            let group = match self.peek().cloned() {
+                Some(TokenKind::Ident(label)) => GroupBy::Label(label),
+                Some(TokenKind::Number(i)) if i.fract() == 0. => GroupBy::Segment(i as usize),
+                _ => {
+                    return Err(ParseError::new(
+                        offset,
+                        "expected a label or a segment index",
+                    ))
+                }
+            };
+            self.pos += 1;
+            groups.push(group);
+            if !self.eat(',') {
+                break;
+            }
+        }
+        self.expect(')')?;
+        Ok(groups)
+    }
+    /// The rest of `aggregate` after the groups
+    fn aggregate(
+        &mut self,
+        aggregation: GroupAggregation,
+        by: Vec<GroupBy>,
+    ) -> Result<Expr, ParseError> {
+        self.expect('(')?;
+        let expr = self.expr()?;
+        self.expect(')')?;
+        Ok(Expr::Aggregate(aggregation, by, Box::new(expr)))
+    }
+}
+
+fn group_aggregation(name: &str) -> Option<GroupAggregation> {
+    Some(match name {
+        "sum" => GroupAggregation::Sum,
+        "avg" => GroupAggregation::Mean,
+        "min" => GroupAggregation::Min,
+        "max" => GroupAggregation::Max,
+        "count" => GroupAggregation::Count,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{consumer::MetricQueue, synthesis::Interpolation};
+
+    use super::*;
+
+    fn parse(s: &str) -> Result<Expression, ParseError> {
+        Expression::parse(s, TimeUnit::Millis)
+    }
+    fn queues(series: &[(&str, &[(Time, f64)])]) -> MetricQueues {
+        let mut metrics = MetricQueues::new();
+        for (key, samples) in series {
+            let mut queue = MetricQueue::new();
+            for &(time, value) in *samples {
+                queue.push(Sample { time, value }, 64).unwrap();
+            }
+            metrics.insert(key.to_string(), Arc::new(queue));
+        }
+        metrics
+    }
+    fn eval(s: &str, metrics: &MetricQueues) -> Vec<(String, Vec<(Time, f64)>)> {
+        let series = parse(s)
+            .unwrap()
+            .series(metrics, &MetricSyntheses::new(), ..);
+        series
+            .unwrap()
+            .into_iter()
+            .map(|(name, span)| {
+                let samples = span.samples.map(|sample| (sample.time, sample.value));
+                (name, samples.collect())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(parse("mem.used").unwrap().is_key());
+        for s in [
+            "100 * mem.used / mem.total",
+            "-(a + b) * 2",
+            "rate(net.bytes[1m])",
+            r#"avg by (region, 0) ("*.cpu")"#,
+        ] {
+            assert!(!parse(s).unwrap().is_key(), "{s}");
+        }
+        assert_eq!(parse("a +").unwrap_err().offset, 3);
+        assert_eq!(parse("a b").unwrap_err().offset, 2);
+        assert_eq!(parse("rate(abc").unwrap_err().offset, 8);
+        assert_eq!(parse("(a) * ").unwrap_err().offset, 6);
+        assert!(parse("rate(a[1w])").is_err());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let huge = format!("1{}", "0".repeat(400));
+        let large = format!("1{}", "0".repeat(300));
+        for s in [
+            format!("rate(a[{huge}s])"),
+            format!("rate(a[{large}d])"),
+            "rate(a[0s])".to_owned(),
+            "rate(a[0.0ms])".to_owned(),
+        ] {
+            assert_eq!(parse(&s).unwrap_err().offset, 7, "{s}");
+        }
+        assert!(parse("rate(a[1.5s])").is_ok());
+    }
+
+    #[test]
+    fn test_empty_window() {
+        for s in ["avg_over_time(a[0.5s])", "rate(a[999ms])"] {
+            let e = Expression::parse(s, TimeUnit::Seconds).unwrap_err();
+            assert_eq!(e.offset, 0, "{s}");
+        }
+        let samples: Vec<Sample> = [0, 0, 1, Time::MAX]
+            .into_iter()
+            .map(|time| Sample { time, value: 1. })
+            .collect();
+        let rolled = rolling(&samples, Time::MAX, Aggregation::Mean);
+        assert_eq!(rolled.len(), 4);
+        assert!(rolled.iter().all(|sample| sample.value == 1.));
+    }
+
+    #[test]
+    fn test_eval_binary() {
+        let metrics = queues(&[
+            ("mem.used", &[(0, 50.), (10, 75.)]),
+            ("mem.total", &[(0, 100.), (10, 100.)]),
+        ]);
+        assert_eq!(
+            eval("100 * mem.used / mem.total", &metrics),
+            [("mem.used".to_string(), vec![(0, 50.), (10, 75.)])]
+        );
+        assert_eq!(
+            eval("1 - -mem.used", &metrics),
+            [("mem.used".to_string(), vec![(0, 51.), (10, 76.)])]
+        );
+        assert_eq!(
+            eval("mem.total / 4 - 5", &metrics),
+            [("mem.total".to_string(), vec![(0, 20.), (10, 20.)])]
+        );
+        // A constant is no series
+        assert!(eval("2 * (3 + 4)", &metrics).is_empty());
+        assert!(eval("missing + 1", &metrics).is_empty());
+    }
+
+    #[test]
+    fn test_eval_queries() {
+        let metrics = queues(&[
+            (
+                "host=a.region=eu.rx",
+                &[(0, 0.), (1000, 100.), (2000, 200.)],
+            ),
+            ("host=b.region=us.rx", &[(0, 20.)]),
+            ("host=a.region=eu.tx", &[(0, 10.), (1000, 20.)]),
+            ("host=b.region=us.tx", &[(0, 40.)]),
+            ("host=a.region=eu.cpu", &[(0, 10.), (1000, 20.)]),
+            ("host=b.region=eu.cpu", &[(0, 30.), (1000, 40.)]),
+            ("host=c.region=us.cpu", &[(0, 5.)]),
+        ]);
+        let series = |name: &str, samples: &[(Time, f64)]| (name.to_string(), samples.to_vec());
+        let rx = "host=a.region=eu.rx";
+        assert_eq!(
+            eval(&format!("rate({rx}[1s])"), &metrics),
+            [series(rx, &[(1000, 100.), (2000, 100.)])]
+        );
+        assert_eq!(
+            eval(&format!("max_over_time({rx}[2s])"), &metrics),
+            [series(rx, &[(0, 0.), (1000, 100.), (2000, 200.)])]
+        );
+        assert_eq!(
+            eval(&format!("avg_over_time({rx}[2s])"), &metrics),
+            [series(rx, &[(0, 0.), (1000, 50.), (2000, 150.)])]
+        );
+        assert_eq!(
+            eval(r#"avg by (region) ("*.cpu")"#, &metrics),
+            [
+                series("region=eu", &[(0, 20.), (1000, 30.)]),
+                series("region=us", &[(0, 5.)]),
+            ]
+        );
+        // Series pair up by name, and a sample without a match at its time is left out
+        assert_eq!(
+            eval(
+                r#"sum by (host) ("*.rx") / sum by (host) ("*.tx")"#,
+                &metrics
+            ),
+            [
+                series("host=a", &[(0, 0.), (1000, 5.)]),
+                series("host=b", &[(0, 0.5)]),
+            ]
+        );
+        assert!(eval(
+            r#"sum by (host) ("*.rx") - sum by (region) ("*.tx")"#,
+            &metrics
+        )
+        .is_empty());
+        // A single series pairs with each of the others
+        assert_eq!(
+            eval(r#""*.cpu" / host=c.region=us.cpu"#, &metrics),
+            [
+                series("host=a.region=eu.cpu", &[(0, 2.)]),
+                series("host=b.region=eu.cpu", &[(0, 6.)]),
+                series("host=c.region=us.cpu", &[(0, 1.)]),
+            ]
+        );
+        // Samples before the range still feed the windows
+        let rate = parse(&format!("rate({rx}[1s])")).unwrap();
+        let spans = rate
+            .series(&metrics, &MetricSyntheses::new(), 1500..)
+            .unwrap();
+        let samples: Vec<Sample> = spans
+            .into_iter()
+            .flat_map(|(_, span)| span.samples)
+            .collect();
+        assert_eq!(samples.len(), 1);
+        assert_eq!((samples[0].time, samples[0].value), (2000, 100.));
+    }
+
+    #[test]
+    fn test_eval_ranges() {
+        let metrics = queues(&[
+            (
+                "x",
+                &[
+                    (0, 0.),
+                    (400, 4.),
+                    (1000, 10.),
+                    (1700, 12.),
+                    (2300, 20.),
+                    (3000, 26.),
+                    (3600, 30.),
+                ],
+            ),
+            ("y", &[(200, 1.), (1500, 2.), (3300, 3.)]),
+            (
+                "host=a.region=eu.cpu",
+                &[(100, 10.), (1200, 20.), (2900, 30.)],
+            ),
+            ("host=b.region=eu.cpu", &[(600, 40.), (2100, 50.)]),
+        ]);
+        let syntheses = MetricSyntheses::new();
+        let alignment = Alignment {
+            tolerance: 100,
+            interpolation: Interpolation::Linear,
+        };
+        for s in [
+            "irate(x)",
+            "deriv(x) + 1",
+            "rate(x[1500ms])",
+            "max_over_time(rate(x[1s])[2s])",
+            "x / y",
+            r#"sum by (region) ("*.cpu")"#,
+        ] {
+            let expression = parse(s).unwrap().with_alignment(alignment).with_step(1000);
+            let all = expression.series(&metrics, &syntheses, ..).unwrap();
+            let all: Vec<(String, Vec<Sample>)> = all
+                .into_iter()
+                .map(|(name, span)| (name, span.samples.collect()))
+                .collect();
+            // Only the samples reaching into the range are read for it
+            for time_range in [
+                RangeAny::from_range(1000..=3000),
+                RangeAny::from_range(2500..),
+                RangeAny::from_range(..1500),
+            ] {
+                let series = expression.series(&metrics, &syntheses, time_range).unwrap();
+                let series: Vec<(String, Vec<(Time, f64)>)> = series
+                    .into_iter()
+                    .map(|(name, span)| {
+                        let samples = span.samples.map(|sample| (sample.time, sample.value));
+                        (name, samples.collect())
+                    })
+                    .collect();
+                let expected: Vec<(String, Vec<(Time, f64)>)> = all
+                    .iter()
+                    .map(|(name, samples)| {
+                        let samples = samples.iter().filter(|sample| {
+                            core::ops::RangeBounds::contains(&time_range, &sample.time)
+                        });
+                        (
+                            name.clone(),
+                            samples.map(|sample| (sample.time, sample.value)).collect(),
+                        )
+                    })
+                    .collect();
+                assert_eq!(series, expected, "{s} over {time_range:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Cross-series aggregation
+//!
+//! Keys are split into `.`-separated segments, and a segment like `region=eu` is the label `region` of value `eu`.
+//! For example, `avg` of `*.cpu` by label `region` over `host=a.region=eu.cpu` and `host=b.region=eu.cpu`.
+
+use std::{collections::BTreeMap, num::NonZeroU64};
+
+use primitive::ops::range::RangeAny;
+
+use crate::{
+    consumer::{matching_keys, KeyPattern, MetricQueues, TimeSeriesSpan},
+    resample::{Fill, Reducer, Resampler},
+    synthesis::{owned_span, sorted_samples},
+    Sample, Time,
+};
+
+/// What series of the same group share
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupBy {
+    /// All the series fall into one group named `""`
+    All,
+    /// The segment at this index
+    Segment(usize),
+    /// The value of this label
+    Label(String),
+}
+impl GroupBy {
+    /// [`None`] if `key` lacks the segment or label
+    pub fn group<'a>(&self, key: &'a str) -> Option<&'a str> {
+        match self {
+            GroupBy::All => Some(""),
+            GroupBy::Segment(i) => key.split('.').nth(*i),
+            GroupBy::Label(label) => key.split('.').find_map(|segment| {
+                let (name, value) = segment.split_once('=')?;
+                (name == label).then_some(value)
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupAggregation {
+    Sum,
+    Mean,
+    Max,
+    Min,
+    /// Of the series with a sample in the bucket
+    Count,
+}
+impl GroupAggregation {
+    fn aggregate(&self, values: &[f64]) -> f64 {
+        match self {
+            GroupAggregation::Sum => values.iter().sum(),
+            GroupAggregation::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            GroupAggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            GroupAggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            GroupAggregation::Count => values.len() as f64,
+        }
+    }
+}
+
+/// [`GroupAggregation`] across the queues matching `pattern` per group
+///
+/// Each series contributes its latest sample within each `step`-long bucket, and the aggregates are timed at the bucket starts.
+#[derive(Debug, Clone)]
+pub struct Grouped {
+    pattern: KeyPattern,
+    group_by: GroupBy,
+    aggregation: GroupAggregation,
+    step: NonZeroU64,
+}
+impl Grouped {
+    pub fn new(
+        pattern: KeyPattern,
+        group_by: GroupBy,
+        aggregation: GroupAggregation,
+        step: NonZeroU64,
+    ) -> Self {
+        Self {
+            pattern,
+            group_by,
+            aggregation,
+            step,
+        }
+    }
+
+    /// One span per group in order of the group names
+    pub fn spans(
+        &self,
+        metrics: &MetricQueues,
+        time_range: impl core::ops::RangeBounds<Time> + Clone,
+    ) -> Vec<(String, TimeSeriesSpan<'static>)> {
+        let series = matching_keys(metrics, &self.pattern).filter_map(|key| {
+            let range = RangeAny::from_range(time_range.clone());
+            Some((key.as_str(), sorted_samples(metrics, key, range)?))
+        });
+        let group = |key: &str| self.group_by.group(key).map(str::to_owned);
+        aggregate(series, group, self.aggregation, self.step.get())
+            .into_iter()
+            .map(|(group, samples)| (group, owned_span(samples)))
+            .collect()
+    }
+}
+
+/// [`GroupAggregation`] of `series` per group in order of the group names
+///
+/// Samples of each series are sorted by time, and a `step` of zero aggregates at each timestamp.
+pub(crate) fn aggregate(
+    series: impl Iterator<Item = (impl AsRef<str>, Vec<Sample>)>,
+    group: impl Fn(&str) -> Option<String>,
+    aggregation: GroupAggregation,
+    step: Time,
+) -> Vec<(String, Vec<Sample>)> {
+    // Values of all the series per bucket start per group
+    let mut groups: BTreeMap<String, BTreeMap<Time, Vec<f64>>> = BTreeMap::new();
+    for (key, samples) in series {
+        let Some(group) = group(key.as_ref()) else {
+            continue;
+        };
+        let buckets = groups.entry(group).or_default();
+        let samples = match NonZeroU64::new(step) {
+            None => samples,
+            Some(step) => Resampler::new(step, Reducer::Last, Fill::Null)
+                .resample_samples(samples.into_iter(), ..),
+        };
+        for sample in samples {
+            buckets.entry(sample.time).or_default().push(sample.value);
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(group, buckets)| {
+            let samples = buckets
+                .into_iter()
+                .map(|(time, values)| Sample {
+                    time,
+                    value: aggregation.aggregate(&values),
+                })
+                .collect();
+            (group, samples)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::consumer::MetricQueue;
+
+    use super::*;
+
+    fn samples(samples: &[(Time, f64)]) -> Vec<Sample> {
+        samples
+            .iter()
+            .map(|&(time, value)| Sample { time, value })
+            .collect()
+    }
+    fn pairs(samples: &[Sample]) -> Vec<(Time, f64)> {
+        samples
+            .iter()
+            .map(|sample| (sample.time, sample.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let series = [
+            ("a", samples(&[(0, 1.), (5, 2.), (12, 4.)])),
+            ("b", samples(&[(3, 6.), (15, 8.)])),
+            ("c", samples(&[(5, 10.)])),
+        ];
+        let group = |key: &str| (key != "c").then(|| "ab".to_owned());
+        let aggregated = aggregate(series.clone().into_iter(), group, GroupAggregation::Sum, 10);
+        assert_eq!(aggregated.len(), 1);
+        assert_eq!(aggregated[0].0, "ab");
+        assert_eq!(pairs(&aggregated[0].1), [(0, 8.), (10, 12.)]);
+
+        let all = |_: &str| Some(String::new());
+        let aggregated = aggregate(series.clone().into_iter(), all, GroupAggregation::Count, 0);
+        assert_eq!(
+            pairs(&aggregated[0].1),
+            [(0, 1.), (3, 1.), (5, 2.), (12, 1.), (15, 1.)]
+        );
+        let aggregated = aggregate(series.into_iter(), all, GroupAggregation::Max, 10);
+        assert_eq!(pairs(&aggregated[0].1), [(0, 10.), (10, 8.)]);
+    }
+
+    #[test]
+    fn test_grouped() {
+        let series: [(&str, &[(Time, f64)]); 5] = [
+            ("host=a.region=eu.cpu", &[(0, 1.), (5, 2.), (12, 4.)]),
+            ("host=b.region=eu.cpu", &[(3, 6.), (15, 8.)]),
+            ("host=c.region=us.cpu", &[(1, 10.)]),
+            ("host=d.cpu", &[(1, 20.)]),
+            ("host=e.region=us.mem", &[(1, 30.)]),
+        ];
+        let mut metrics = MetricQueues::new();
+        for (key, series) in series {
+            let mut queue = MetricQueue::new();
+            for &sample in &samples(series) {
+                queue.push(sample, 16).unwrap();
+            }
+            metrics.insert(key.to_owned(), Arc::new(queue));
+        }
+        let grouped = Grouped::new(
+            KeyPattern::parse("host=*.cpu").unwrap(),
+            GroupBy::Label("region".to_owned()),
+            GroupAggregation::Mean,
+            NonZeroU64::new(10).unwrap(),
+        );
+        let spans = grouped.spans(&metrics, ..);
+        let spans: Vec<(String, Vec<(Time, f64)>)> = spans
+            .into_iter()
+            .map(|(group, span)| (group, pairs(&span.samples.collect::<Vec<_>>())))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                ("eu".to_owned(), vec![(0, 4.), (10, 6.)]),
+                ("us".to_owned(), vec![(0, 10.)]),
+            ]
+        );
+        let counts: Vec<usize> = grouped
+            .spans(&metrics, 10..)
+            .into_iter()
+            .map(|(_, span)| span.count)
+            .collect();
+        assert_eq!(counts, [1, 0]);
+    }
+}
@@ -1,13 +1,79 @@
 pub mod alert;
 pub mod buf;
+pub mod chunked;
+pub mod clock;
 pub mod codec;
 pub mod consumer;
 pub mod exporter;
+pub mod expr;
+pub mod group;
+pub mod resample;
+pub mod rollup;
+pub mod shared;
+pub mod snapshot;
+pub mod storage;
+pub mod synthesis;
 pub mod view;
+pub mod wal;
+pub mod window;
+
+use std::time::Duration;
 
 pub type MetricKey = String;
 pub type Time = u64;
 
+/// What one tick of [`Time`] stands for, counted from the Unix epoch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TimeUnit {
+    Seconds,
+    #[default]
+    Millis,
+    Micros,
+    Nanos,
+}
+impl TimeUnit {
+    pub fn ticks_per_second(&self) -> u64 {
+        match self {
+            TimeUnit::Seconds => 1,
+            TimeUnit::Millis => 1_000,
+            TimeUnit::Micros => 1_000_000,
+            TimeUnit::Nanos => 1_000_000_000,
+        }
+    }
+
+    /// Saturates at [`Time::MAX`]
+    pub fn from_duration(&self, duration: Duration) -> Time {
+        let ticks = match self {
+            TimeUnit::Seconds => u128::from(duration.as_secs()),
+            TimeUnit::Millis => duration.as_millis(),
+            TimeUnit::Micros => duration.as_micros(),
+            TimeUnit::Nanos => duration.as_nanos(),
+        };
+        Time::try_from(ticks).unwrap_or(Time::MAX)
+    }
+    pub fn to_duration(&self, time: Time) -> Duration {
+        match self {
+            TimeUnit::Seconds => Duration::from_secs(time),
+            TimeUnit::Millis => Duration::from_millis(time),
+            TimeUnit::Micros => Duration::from_micros(time),
+            TimeUnit::Nanos => Duration::from_nanos(time),
+        }
+    }
+    /// Truncates when converting to a coarser unit and saturates when converting to a finer one
+    pub fn convert(&self, time: Time, to: TimeUnit) -> Time {
+        let from = u128::from(self.ticks_per_second());
+        let to_ticks = u128::from(to.ticks_per_second());
+        let time = u128::from(time) * to_ticks / from;
+        Time::try_from(time).unwrap_or(Time::MAX)
+    }
+    pub fn as_secs_f64(&self, time: Time) -> f64 {
+        time as f64 / self.ticks_per_second() as f64
+    }
+    pub fn as_millis_f64(&self, time: Time) -> f64 {
+        self.as_secs_f64(time) * 1_000.
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Sample {
     pub time: Time,
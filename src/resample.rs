@@ -0,0 +1,358 @@
+//! Resampling onto a fixed grid
+//!
+//! The grid points are the multiples of a step, each standing for the bucket of samples up to the next one.
+
+use std::{collections::BTreeMap, num::NonZeroU64};
+
+use primitive::ops::range::RangeAny;
+
+use crate::{
+    consumer::TimeSeriesSpan,
+    synthesis::{owned_span, Reach},
+    view::{MetricSources, MetricSynthesis, SynthesisError},
+    Sample, Time,
+};
+
+/// Most points [`Resampler::resample`] puts on a grid
+pub const MAX_GRID_POINTS: usize = 1 << 16;
+
+/// What a bucket of samples comes down to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Reducer {
+    /// The latest sample
+    #[default]
+    Last,
+    Mean,
+    Sum,
+    Min,
+    Max,
+    /// Zero for an empty bucket regardless of the [`Fill`]
+    Count,
+}
+impl Reducer {
+    /// `values` are in order of time
+    fn reduce(&self, values: &[f64]) -> f64 {
+        match self {
+            Reducer::Last => *values.last().unwrap(),
+            Reducer::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            Reducer::Sum => values.iter().sum(),
+            Reducer::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Reducer::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Reducer::Count => values.len() as f64,
+        }
+    }
+}
+
+/// What an empty bucket takes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fill {
+    /// No value
+    #[default]
+    Null,
+    /// The value of the latest earlier bucket
+    Previous,
+    /// The line between the values of the surrounding buckets
+    Linear,
+    Zero,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resampler {
+    step: Time,
+    reducer: Reducer,
+    fill: Fill,
+}
+impl Resampler {
+    pub fn new(step: NonZeroU64, reducer: Reducer, fill: Fill) -> Self {
+        Self {
+            step: step.get(),
+            reducer,
+            fill,
+        }
+    }
+    pub fn step(&self) -> Time {
+        self.step
+    }
+    pub fn reducer(&self) -> Reducer {
+        self.reducer
+    }
+    pub fn fill(&self) -> Fill {
+        self.fill
+    }
+    /// How far out of a range the samples filling its buckets are
+    ///
+    /// [`Fill::Previous`] and [`Fill::Linear`] carry values from buckets any distance away.
+    pub(crate) fn reach(&self) -> Reach {
+        let last_bucket = Reach::after(self.step - 1);
+        match self.fill {
+            _ if self.reducer == Reducer::Count => last_bucket,
+            Fill::Null | Fill::Zero => last_bucket,
+            Fill::Previous => last_bucket.and(Reach::before(Time::MAX)),
+            Fill::Linear => Reach::before(Time::MAX).and(Reach::after(Time::MAX)),
+        }
+    }
+
+    /// The grid points within `time_range`, or of the buckets of the first and the last samples where it is unbounded
+    ///
+    /// There is a point for every step however few samples there are, up to [`MAX_GRID_POINTS`] from the start;
+    /// see [`Resampler::resample_span`] otherwise.
+    /// Samples out of `time_range` are still used to fill the buckets within it.
+    pub fn resample(
+        &self,
+        span: TimeSeriesSpan<'_>,
+        time_range: impl core::ops::RangeBounds<Time>,
+    ) -> Vec<(Time, Option<f64>)> {
+        let (buckets, samples) = self.buckets(span.samples);
+        let Some((start, end)) = grid_bounds(time_range, samples, self.step) else {
+            return vec![];
+        };
+        let mut points = vec![];
+        let mut time = start;
+        loop {
+            points.push((time, self.value_at(&buckets, time)));
+            match time.checked_add(self.step) {
+                Some(next) if next <= end && points.len() < MAX_GRID_POINTS => time = next,
+                _ => break,
+            }
+        }
+        points
+    }
+    /// [`Resampler::resample`] without the empty buckets
+    ///
+    /// Only the buckets with samples are visited under [`Fill::Null`].
+    pub fn resample_span(
+        &self,
+        span: TimeSeriesSpan<'_>,
+        time_range: impl core::ops::RangeBounds<Time>,
+    ) -> TimeSeriesSpan<'static> {
+        owned_span(self.resample_samples(span.samples, time_range))
+    }
+
+    pub(crate) fn resample_samples(
+        &self,
+        samples: impl Iterator<Item = Sample>,
+        time_range: impl core::ops::RangeBounds<Time>,
+    ) -> Vec<Sample> {
+        if self.fill != Fill::Null || self.reducer == Reducer::Count {
+            let span = owned_span(samples.collect());
+            let points = self.resample(span, time_range).into_iter();
+            let samples = points.filter_map(|(time, value)| {
+                Some(Sample {
+                    time,
+                    value: value?,
+                })
+            });
+            return samples.collect();
+        }
+        let (buckets, samples) = self.buckets(samples);
+        let Some((start, end)) = grid_bounds(time_range, samples, self.step) else {
+            return vec![];
+        };
+        let buckets = buckets.range(start..=end);
+        let samples = buckets.map(|(&time, &value)| Sample { time, value });
+        samples.collect()
+    }
+    /// Reduced values by bucket start and the times of the first and the last samples
+    fn buckets(
+        &self,
+        samples: impl Iterator<Item = Sample>,
+    ) -> (BTreeMap<Time, f64>, Option<(Time, Time)>) {
+        let mut samples = samples.collect::<Vec<_>>();
+        samples.sort_by_key(|sample| sample.time);
+        let bucket = |time: Time| time / self.step * self.step;
+        let mut buckets: BTreeMap<Time, f64> = BTreeMap::new();
+        for samples in samples.chunk_by(|a, b| bucket(a.time) == bucket(b.time)) {
+            let values = samples
+                .iter()
+                .map(|sample| sample.value)
+                .collect::<Vec<_>>();
+            buckets.insert(bucket(samples[0].time), self.reducer.reduce(&values));
+        }
+        let bounds = samples.first().zip(samples.last());
+        let bounds = bounds.map(|(first, last)| (first.time, last.time));
+        (buckets, bounds)
+    }
+    fn value_at(&self, buckets: &BTreeMap<Time, f64>, time: Time) -> Option<f64> {
+        if let Some(&value) = buckets.get(&time) {
+            return Some(value);
+        }
+        let prev = || buckets.range(..time).next_back();
+        let next = || buckets.range(time..).next();
+        if self.reducer == Reducer::Count {
+            return Some(0.);
+        }
+        match self.fill {
+            Fill::Null => None,
+            Fill::Previous => prev().map(|(_, &value)| value),
+            Fill::Linear => {
+                let ((&prev_time, &prev), (&next_time, &next)) = (prev()?, next()?);
+                let progress = (time - prev_time) as f64 / (next_time - prev_time) as f64;
+                Some(prev + (next - prev) * progress)
+            }
+            Fill::Zero => Some(0.),
+        }
+    }
+}
+
+/// The first grid point within `time_range` and its end
+///
+/// Where unbounded, the range starts at the bucket of the first sample and ends at the last sample.
+fn grid_bounds(
+    time_range: impl core::ops::RangeBounds<Time>,
+    samples: Option<(Time, Time)>,
+    step: Time,
+) -> Option<(Time, Time)> {
+    let start = match time_range.start_bound() {
+        core::ops::Bound::Included(&start) => start.checked_next_multiple_of(step)?,
+        core::ops::Bound::Excluded(&start) => {
+            start.checked_add(1)?.checked_next_multiple_of(step)?
+        }
+        core::ops::Bound::Unbounded => samples?.0 / step * step,
+    };
+    let end = match time_range.end_bound() {
+        core::ops::Bound::Included(&end) => end,
+        core::ops::Bound::Excluded(&end) => end.checked_sub(1)?,
+        core::ops::Bound::Unbounded => samples?.1,
+    };
+    (start <= end).then_some((start, end))
+}
+
+/// Another [`MetricSynthesis`] resampled, without the empty buckets
+#[derive(Debug)]
+pub struct Resampled {
+    source: Box<dyn MetricSynthesis>,
+    resampler: Resampler,
+}
+impl Resampled {
+    pub fn new(source: Box<dyn MetricSynthesis>, resampler: Resampler) -> Self {
+        Self { source, resampler }
+    }
+}
+impl MetricSynthesis for Resampled {
+    fn span<'a>(
+        &'a self,
+        sources: &MetricSources<'a>,
+        time_range: RangeAny<Time>,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        // Samples out of the range are needed to fill the buckets at its ends
+        let range = self.resampler.reach().widen(&time_range);
+        let span = self.source.span(sources, range)?;
+        Ok(self.resampler.resample_span(span, time_range))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        consumer::{MetricQueue, MetricQueues},
+        view::MetricSyntheses,
+        window::Series,
+    };
+
+    use super::*;
+
+    fn span(samples: &[(Time, f64)]) -> TimeSeriesSpan<'static> {
+        let samples = samples.iter().map(|&(time, value)| Sample { time, value });
+        owned_span(samples.collect())
+    }
+    fn resampler(reducer: Reducer, fill: Fill) -> Resampler {
+        Resampler::new(NonZeroU64::new(10).unwrap(), reducer, fill)
+    }
+
+    #[test]
+    fn test_fills() {
+        let samples = [(0, 1.), (5, 3.), (30, 6.)];
+        let values = |reducer, fill| -> Vec<Option<f64>> {
+            let points = resampler(reducer, fill).resample(span(&samples), 0..=40);
+            points.into_iter().map(|(_, value)| value).collect()
+        };
+        assert_eq!(
+            values(Reducer::Last, Fill::Null),
+            [Some(3.), None, None, Some(6.), None]
+        );
+        assert_eq!(
+            values(Reducer::Mean, Fill::Previous),
+            [Some(2.), Some(2.), Some(2.), Some(6.), Some(6.)]
+        );
+        assert_eq!(
+            values(Reducer::Last, Fill::Linear),
+            [Some(3.), Some(4.), Some(5.), Some(6.), None]
+        );
+        assert_eq!(
+            values(Reducer::Sum, Fill::Zero),
+            [Some(4.), Some(0.), Some(0.), Some(6.), Some(0.)]
+        );
+        assert_eq!(
+            values(Reducer::Count, Fill::Null),
+            [Some(2.), Some(0.), Some(0.), Some(1.), Some(0.)]
+        );
+    }
+
+    #[test]
+    fn test_resample_span() {
+        let samples = [(3, 1.), (5, 3.), (Time::MAX - 1, 6.)];
+        let resampled = resampler(Reducer::Max, Fill::Null).resample_span(span(&samples), ..);
+        let points: Vec<(Time, f64)> = resampled
+            .samples
+            .map(|sample| (sample.time, sample.value))
+            .collect();
+        assert_eq!(points, [(0, 3.), (Time::MAX / 10 * 10, 6.)]);
+
+        let resampled =
+            resampler(Reducer::Last, Fill::Previous).resample_span(span(&samples), 10..=30);
+        let times: Vec<Time> = resampled.samples.map(|sample| sample.time).collect();
+        assert_eq!(times, [10, 20, 30]);
+        // The range starts at the next grid point
+        let resampled =
+            resampler(Reducer::Last, Fill::Previous).resample_span(span(&samples), 5..=30);
+        let times: Vec<Time> = resampled.samples.map(|sample| sample.time).collect();
+        assert_eq!(times, [10, 20, 30]);
+        let points = resampler(Reducer::Last, Fill::Null).resample(span(&samples), 11..20);
+        assert!(points.is_empty());
+    }
+
+    #[test]
+    fn test_max_grid_points() {
+        let samples = [(0, 1.), (Time::MAX, 2.)];
+        for resampler in [
+            resampler(Reducer::Last, Fill::Zero),
+            resampler(Reducer::Count, Fill::Null),
+        ] {
+            let points = resampler.resample(span(&samples), ..);
+            assert_eq!(points.len(), MAX_GRID_POINTS);
+            let points = resampler.resample_span(span(&samples), 0..=Time::MAX);
+            assert_eq!(points.count, MAX_GRID_POINTS);
+        }
+    }
+
+    #[test]
+    fn test_resampled() {
+        let mut queue = MetricQueue::new();
+        for (time, value) in [(3, 1.), (5, 3.), (12, 4.), (30, 6.)] {
+            queue.push(Sample { time, value }, 16).unwrap();
+        }
+        let metrics = MetricQueues::from([("a".to_string(), Arc::new(queue))]);
+        let syntheses = MetricSyntheses::new();
+        let sources = MetricSources::new(&metrics, &syntheses);
+        let resampled = |fill, time_range: RangeAny<Time>| {
+            let source = Box::new(Series::new("a".into()));
+            let resampled = Resampled::new(source, resampler(Reducer::Mean, fill));
+            let span = resampled.span(&sources, time_range);
+            let samples = span.unwrap().samples;
+            samples
+                .map(|sample| (sample.time, sample.value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            resampled(Fill::Null, RangeAny::from_range(..)),
+            [(0, 2.), (10, 4.), (30, 6.)]
+        );
+        // The buckets at the ends of the range are filled from the samples out of it
+        assert_eq!(
+            resampled(Fill::Linear, RangeAny::from_range(10..=20)),
+            [(10, 4.), (20, 5.)]
+        );
+    }
+}
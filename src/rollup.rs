@@ -0,0 +1,236 @@
+use std::{
+    collections::VecDeque,
+    ops::{Bound, RangeInclusive},
+};
+
+use crate::{
+    chunked::{ChunkedDeque, ChunkedSpan},
+    Sample, Time, TimeUnit,
+};
+
+/// Bytes of an encoded [`RollupBucket`]
+pub(crate) const ROLLUP_BUCKET_SIZE: usize = 8 * 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RollupTier {
+    /// Width of each bucket
+    pub step: Time,
+    /// Maximum number of buckets kept
+    pub capacity: usize,
+}
+
+/// Summary of the samples falling in `[start, start + step)`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RollupBucket {
+    pub start: Time,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: u64,
+    /// Value of the latest sample
+    pub last: f64,
+    last_time: Time,
+}
+impl RollupBucket {
+    fn new(start: Time, sample: Sample) -> Self {
+        Self {
+            start,
+            min: sample.value,
+            max: sample.value,
+            sum: sample.value,
+            count: 1,
+            last: sample.value,
+            last_time: sample.time,
+        }
+    }
+
+    fn add(&mut self, sample: Sample) {
+        self.min = self.min.min(sample.value);
+        self.max = self.max.max(sample.value);
+        self.sum += sample.value;
+        self.count += 1;
+        if self.last_time <= sample.time {
+            self.last = sample.value;
+            self.last_time = sample.time;
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.start.to_be_bytes());
+        buf.extend(self.min.to_be_bytes());
+        buf.extend(self.max.to_be_bytes());
+        buf.extend(self.sum.to_be_bytes());
+        buf.extend(self.count.to_be_bytes());
+        buf.extend(self.last.to_be_bytes());
+        buf.extend(self.last_time.to_be_bytes());
+    }
+    pub(crate) fn decode(buf: [u8; ROLLUP_BUCKET_SIZE]) -> Self {
+        let field = |i: usize| -> [u8; 8] { buf[i * 8..(i + 1) * 8].try_into().unwrap() };
+        Self {
+            start: Time::from_be_bytes(field(0)),
+            min: f64::from_be_bytes(field(1)),
+            max: f64::from_be_bytes(field(2)),
+            sum: f64::from_be_bytes(field(3)),
+            count: u64::from_be_bytes(field(4)),
+            last: f64::from_be_bytes(field(5)),
+            last_time: Time::from_be_bytes(field(6)),
+        }
+    }
+    pub(crate) fn convert(self, from: TimeUnit, to: TimeUnit) -> Self {
+        Self {
+            start: from.convert(self.start, to),
+            last_time: from.convert(self.last_time, to),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rollup {
+    tier: RollupTier,
+    buckets: ChunkedDeque<RollupBucket>,
+}
+impl Rollup {
+    pub fn new(tier: RollupTier) -> Self {
+        Self {
+            tier,
+            buckets: ChunkedDeque::new(),
+        }
+    }
+
+    pub fn tier(&self) -> &RollupTier {
+        &self.tier
+    }
+    pub fn buckets(&self) -> &ChunkedDeque<RollupBucket> {
+        &self.buckets
+    }
+    pub fn first_time(&self) -> Option<Time> {
+        self.buckets.front().map(|bucket| bucket.start)
+    }
+    /// Takes `buckets` in time order in place of its own, keeping the latest of them up to the capacity
+    pub(crate) fn set_buckets(&mut self, mut buckets: VecDeque<RollupBucket>) {
+        let excess = buckets.len().saturating_sub(self.tier.capacity);
+        buckets.drain(..excess);
+        self.buckets = buckets.into_iter().collect();
+    }
+
+    pub fn push(&mut self, sample: Sample) {
+        let step = self.tier.step.max(1);
+        let start = sample.time - sample.time % step;
+        let pos = self.buckets.partition_point(|bucket| bucket.start < start);
+        if self
+            .buckets
+            .get(pos)
+            .is_some_and(|bucket| bucket.start == start)
+        {
+            self.buckets.get_mut(pos).unwrap().add(sample);
+            return;
+        }
+        // Older than all the buckets kept
+        if pos == 0 && self.buckets.len() == self.tier.capacity {
+            return;
+        }
+        self.buckets.insert(pos, RollupBucket::new(start, sample));
+        if self.tier.capacity < self.buckets.len() {
+            self.buckets.pop_front();
+        }
+    }
+
+    /// Replaces the value `old` of a sample at `time` with `new`
+    ///
+    /// Returns the time of the bucket if `old` was its min or max and the samples are needed to find the new one,
+    /// which [`Rollup::set_extremes`] takes.
+    pub fn replace(&mut self, time: Time, old: f64, new: f64) -> Option<RangeInclusive<Time>> {
+        let step = self.tier.step.max(1);
+        let start = time - time % step;
+        let pos = self.buckets.partition_point(|bucket| bucket.start < start);
+        if self.buckets.get(pos)?.start != start {
+            return None;
+        }
+        let bucket = self.buckets.get_mut(pos).unwrap();
+        bucket.sum += new - old;
+        if bucket.last_time == time {
+            bucket.last = new;
+        }
+        let stale = (old == bucket.min && old < new) || (old == bucket.max && new < old);
+        bucket.min = bucket.min.min(new);
+        bucket.max = bucket.max.max(new);
+        stale.then(|| start..=start.saturating_add(step - 1))
+    }
+    /// Sets the min and the max of the bucket starting at `start`
+    pub fn set_extremes(&mut self, start: Time, min: f64, max: f64) {
+        let pos = self.buckets.partition_point(|bucket| bucket.start < start);
+        if self
+            .buckets
+            .get(pos)
+            .is_some_and(|bucket| bucket.start == start)
+        {
+            let bucket = self.buckets.get_mut(pos).unwrap();
+            bucket.min = min;
+            bucket.max = max;
+        }
+    }
+
+    /// Indices of the buckets overlapping `range`
+    fn overlapping(&self, range: &RangeInclusive<Time>) -> core::ops::Range<usize> {
+        let step = self.tier.step.max(1);
+        let start = self
+            .buckets
+            .partition_point(|bucket| bucket.start.saturating_add(step) <= *range.start());
+        let end = self
+            .buckets
+            .partition_point(|bucket| bucket.start <= *range.end());
+        start..end.max(start)
+    }
+    /// Whether `range` covers only part of a bucket starting before `raw_since`, whose samples are no longer there to rebuild it from
+    pub fn cuts(&self, range: &RangeInclusive<Time>, raw_since: Time) -> bool {
+        let step = self.tier.step.max(1);
+        let overlapping = self.overlapping(range);
+        if overlapping.is_empty() {
+            return false;
+        }
+        let (first, last) = (
+            &self.buckets[overlapping.start],
+            &self.buckets[overlapping.end - 1],
+        );
+        let cut_first = first.start < *range.start();
+        let cut_last = *range.end() < last.start.saturating_add(step - 1);
+        (cut_first && first.start < raw_since) || (cut_last && last.start < raw_since)
+    }
+
+    /// Removes the buckets overlapping `range` and returns the time they covered
+    pub fn delete(&mut self, range: RangeInclusive<Time>) -> Option<RangeInclusive<Time>> {
+        let step = self.tier.step.max(1);
+        let core::ops::Range { start, end } = self.overlapping(&range);
+        if end <= start {
+            return None;
+        }
+        let window =
+            self.buckets[start].start..=self.buckets[end - 1].start.saturating_add(step - 1);
+        self.buckets.remove_range(start..end);
+        Some(window)
+    }
+
+    /// Buckets starting within `range`
+    pub fn span(&self, range: impl core::ops::RangeBounds<Time>) -> ChunkedSpan<'_, RollupBucket> {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => self.buckets.partition_point(|bucket| bucket.start < start),
+            Bound::Excluded(&start) => self.buckets.partition_point(|bucket| bucket.start <= start),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => self.buckets.partition_point(|bucket| bucket.start <= end),
+            Bound::Excluded(&end) => self.buckets.partition_point(|bucket| bucket.start < end),
+            Bound::Unbounded => self.buckets.len(),
+        };
+        self.buckets.span(start..end)
+    }
+
+    pub fn bytes(&self) -> usize {
+        core::mem::size_of::<Self>() + self.buckets.len() * core::mem::size_of::<RollupBucket>()
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, RwLock},
+};
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+    consumer::{MetricConsumer, MetricQueues},
+    TimeUnit,
+};
+
+/// A [`MetricConsumer`] that can be shared between tasks
+///
+/// Writers take turns on the consumer while readers work on the queues as of the last [`SharedMetricConsumerGuard::publish`], so reads never wait for ingestion and vice versa.
+#[derive(Debug)]
+pub struct SharedMetricConsumer {
+    consumer: Mutex<MetricConsumer>,
+    metrics: RwLock<Arc<MetricQueues>>,
+    time_unit: TimeUnit,
+}
+impl SharedMetricConsumer {
+    pub fn new(consumer: MetricConsumer) -> Self {
+        let metrics = RwLock::new(Arc::new(consumer.metrics().clone()));
+        let time_unit = consumer.time_unit();
+        Self {
+            consumer: Mutex::new(consumer),
+            metrics,
+            time_unit,
+        }
+    }
+
+    /// Changes are invisible to readers until published
+    pub async fn lock(&self) -> SharedMetricConsumerGuard<'_> {
+        SharedMetricConsumerGuard {
+            consumer: self.consumer.lock().await,
+            metrics: &self.metrics,
+        }
+    }
+    /// Queues as of the last publish
+    pub fn metrics(&self) -> Arc<MetricQueues> {
+        self.metrics.read().unwrap().clone()
+    }
+    pub fn time_unit(&self) -> TimeUnit {
+        self.time_unit
+    }
+}
+
+/// Exclusive access to the consumer of a [`SharedMetricConsumer`]
+#[derive(Debug)]
+pub struct SharedMetricConsumerGuard<'a> {
+    consumer: MutexGuard<'a, MetricConsumer>,
+    metrics: &'a RwLock<Arc<MetricQueues>>,
+}
+impl SharedMetricConsumerGuard<'_> {
+    /// Copies the map of queues, whose queues stay shared until the consumer writes to them
+    ///
+    /// A queue written to afterwards copies the pointers to its chunks and then only the chunks the writes land in.
+    pub fn publish(&self) {
+        let metrics = Arc::new(self.consumer.metrics().clone());
+        *self.metrics.write().unwrap() = metrics;
+    }
+}
+impl Deref for SharedMetricConsumerGuard<'_> {
+    type Target = MetricConsumer;
+    fn deref(&self) -> &MetricConsumer {
+        &self.consumer
+    }
+}
+impl DerefMut for SharedMetricConsumerGuard<'_> {
+    fn deref_mut(&mut self) -> &mut MetricConsumer {
+        &mut self.consumer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Sample;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_shares_queues() {
+        let shared = SharedMetricConsumer::new(MetricConsumer::new(16));
+        let (a, b) = ("a".to_string(), "b".to_string());
+        let sample = Sample { time: 1, value: 1. };
+        let mut consumer = shared.lock().await;
+        consumer.push(&a)(sample).unwrap();
+        consumer.push(&b)(sample).unwrap();
+        consumer.publish();
+        consumer.push(&a)(Sample { time: 2, ..sample }).unwrap();
+        let published = shared.metrics();
+        assert!(Arc::ptr_eq(&published["b"], &consumer.metrics()["b"]));
+        assert_eq!(published["a"].len(), 1);
+        assert_eq!(consumer.metrics()["a"].len(), 2);
+    }
+}
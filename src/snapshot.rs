@@ -0,0 +1,445 @@
+//! Whole-consumer snapshots
+//!
+//! A snapshot is a header with the settings of a [`MetricConsumer`],
+//! the rollup buckets of its queues as [`crate::codec::encode_checked`] records,
+//! and one stream of [`crate::codec::encode_checked_frames`] of all its queues.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    codec::{
+        decode_key, decode_time_unit, encode_checked, encode_checked_frames, encode_key,
+        encode_time_unit, read_checked,
+    },
+    consumer::{
+        Admission, CardinalityLimits, DuplicatePolicy, Eviction, KeyPattern, LatePolicy,
+        MemoryBudget, MetricConsumer, MetricQueueConfig,
+    },
+    exporter::copy_checked_frames,
+    rollup::{RollupBucket, RollupTier, ROLLUP_BUCKET_SIZE},
+    MetricKey, Time, TimeUnit,
+};
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"MSNP";
+const SNAPSHOT_VERSION: u8 = 2;
+/// Buckets of each rollup tier by its step
+type TierBuckets = Vec<(Time, VecDeque<RollupBucket>)>;
+
+/// Buckets per rollup record
+const ROLLUP_RECORD_BUCKETS: usize = 1 << 12;
+/// Key, the time the raw samples are evicted until, step, bucket count and buckets
+const MAX_ROLLUP_RECORD_SIZE: usize =
+    2 + u16::MAX as usize + 9 + 8 + 2 + ROLLUP_RECORD_BUCKETS * ROLLUP_BUCKET_SIZE;
+
+/// How [`read_snapshot`] treats the consumer it loads into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreMode {
+    /// Keeps the queues and settings of the consumer and pushes the snapshot samples on top
+    ///
+    /// Rollup buckets are only restored to the queues the consumer does not have.
+    Merge,
+    /// Deletes the queues of the consumer, including from its storage and log, and takes the snapshot settings
+    Replace,
+}
+
+pub fn write_snapshot(consumer: &MetricConsumer, buf: &mut Vec<u8>) {
+    buf.extend(SNAPSHOT_MAGIC);
+    buf.push(SNAPSHOT_VERSION);
+    buf.extend(encode_time_unit(consumer.time_unit()));
+    encode_u64(buf, consumer.queue_size() as u64);
+    encode_queue_config(buf, consumer.default_queue_config());
+    encode_u64(buf, consumer.queue_configs().len() as u64);
+    for (pattern, config) in consumer.queue_configs() {
+        encode_key_pattern(buf, pattern);
+        encode_queue_config(buf, config);
+    }
+    match consumer.memory_budget() {
+        Some(budget) => {
+            buf.push(1);
+            encode_u64(buf, budget.bytes as u64);
+            buf.push(match budget.eviction {
+                Eviction::OldestSamples => 0,
+                Eviction::LeastRecentlyWritten => 1,
+            });
+        }
+        None => buf.push(0),
+    }
+    encode_cardinality_limits(buf, consumer.cardinality_limits());
+    let count_pos = buf.len();
+    encode_u64(buf, 0);
+    let mut count = 0;
+    for (key, queue) in consumer.metrics() {
+        for rollup in queue.rollups() {
+            let buckets: Vec<&RollupBucket> = rollup.buckets().iter().collect();
+            for buckets in buckets.chunks(ROLLUP_RECORD_BUCKETS) {
+                encode_checked(buf, |buf| {
+                    encode_key(buf, key);
+                    encode_option_u64(buf, queue.evicted_until());
+                    encode_u64(buf, rollup.tier().step);
+                    buf.extend((buckets.len() as u16).to_be_bytes());
+                    for bucket in buckets {
+                        bucket.encode(buf);
+                    }
+                });
+                count += 1;
+            }
+        }
+    }
+    buf[count_pos..count_pos + 8].copy_from_slice(&u64::to_be_bytes(count));
+    for (key, queue) in consumer.metrics() {
+        let span = queue.span(..);
+        let samples = span.iter().copied();
+        encode_checked_frames(buf, key, consumer.time_unit(), samples);
+    }
+}
+
+/// Times in the snapshot settings are converted to the time unit of `consumer`
+pub async fn read_snapshot<R>(
+    rdr: &mut R,
+    consumer: &mut MetricConsumer,
+    mode: RestoreMode,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut magic = [0; 4];
+    rdr.read_exact(&mut magic).await?;
+    let version = rdr.read_u8().await?;
+    if magic != SNAPSHOT_MAGIC || version != SNAPSHOT_VERSION {
+        return Err(invalid_data("not a snapshot"));
+    }
+    let time_unit = decode_time_unit([rdr.read_u8().await?])?;
+    let mut settings = SettingsReader {
+        rdr: &mut *rdr,
+        from: time_unit,
+        to: consumer.time_unit(),
+    };
+    let queue_size = settings.rdr.read_u64().await?;
+    let default_config = settings.queue_config().await?;
+    let mut configs = vec![];
+    for _ in 0..settings.rdr.read_u64().await? {
+        let pattern = settings.key_pattern().await?;
+        let config = settings.queue_config().await?;
+        configs.push((pattern, config));
+    }
+    let memory_budget = match settings.rdr.read_u8().await? {
+        0 => None,
+        _ => {
+            let bytes = settings.rdr.read_u64().await? as usize;
+            let eviction = match settings.rdr.read_u8().await? {
+                0 => Eviction::OldestSamples,
+                1 => Eviction::LeastRecentlyWritten,
+                _ => return Err(invalid_data("unknown eviction")),
+            };
+            Some(MemoryBudget { bytes, eviction })
+        }
+    };
+    let cardinality = settings.cardinality_limits().await?;
+    let mut rollups: HashMap<MetricKey, (Option<Time>, TierBuckets)> = HashMap::new();
+    let mut payload = vec![];
+    for _ in 0..settings.rdr.read_u64().await? {
+        if !read_checked(settings.rdr, &mut payload, MAX_ROLLUP_RECORD_SIZE).await? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (key, evicted_until, step, buckets) =
+            decode_rollup_record(&payload, time_unit, consumer.time_unit())?;
+        let entry = rollups.entry(key).or_default();
+        entry.0 = entry.0.max(evicted_until);
+        match entry.1.iter_mut().find(|(other, _)| *other == step) {
+            Some((_, existing)) => existing.extend(buckets),
+            None => entry.1.push((step, buckets)),
+        }
+    }
+
+    if mode == RestoreMode::Replace {
+        // Left in the storage and the log, they would come back on the next start
+        consumer.delete_matching(&KeyPattern::Prefix(String::new()))?;
+        consumer.clear();
+        consumer.set_queue_size(queue_size as usize);
+        consumer.set_default_queue_config(default_config);
+        consumer.set_queue_configs(configs);
+        consumer.set_cardinality_limits(cardinality);
+        // Enforced once all samples are in
+        consumer.set_memory_budget(None);
+    }
+    if mode == RestoreMode::Merge {
+        rollups.retain(|key, _| !consumer.metrics().contains_key(key));
+    }
+    copy_checked_frames(rdr, consumer).await?;
+    for (key, (evicted_until, rollups)) in rollups {
+        consumer.restore_rollups(&key, evicted_until, rollups);
+    }
+    if mode == RestoreMode::Replace {
+        consumer.set_memory_budget(memory_budget);
+    }
+    Ok(())
+}
+
+fn encode_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend(n.to_be_bytes());
+}
+fn encode_option_u64(buf: &mut Vec<u8>, n: Option<u64>) {
+    match n {
+        Some(n) => {
+            buf.push(1);
+            encode_u64(buf, n);
+        }
+        None => buf.push(0),
+    }
+}
+fn encode_key_pattern(buf: &mut Vec<u8>, pattern: &KeyPattern) {
+    let (tag, s) = match pattern {
+        KeyPattern::Exact(key) => (0, key.as_str()),
+        KeyPattern::Prefix(prefix) => (1, prefix.as_str()),
+        KeyPattern::Glob(glob) => (2, glob.as_str()),
+        KeyPattern::Regex(re) => (3, re.as_str()),
+    };
+    buf.push(tag);
+    encode_key(buf, s);
+}
+fn encode_queue_config(buf: &mut Vec<u8>, config: &MetricQueueConfig) {
+    match config.late_policy {
+        LatePolicy::Append => buf.push(0),
+        LatePolicy::Insert { tolerance } => {
+            buf.push(1);
+            encode_u64(buf, tolerance);
+        }
+        LatePolicy::Drop => buf.push(2),
+        LatePolicy::Reject => buf.push(3),
+    }
+    let max_age = config.max_age.map(|max_age| max_age.as_nanos() as u64);
+    encode_option_u64(buf, max_age);
+    encode_u64(buf, config.rollups.len() as u64);
+    for tier in &config.rollups {
+        encode_u64(buf, tier.step);
+        encode_u64(buf, tier.capacity as u64);
+    }
+    let idle_ttl = config.idle_ttl.map(|idle_ttl| idle_ttl.as_nanos() as u64);
+    encode_option_u64(buf, idle_ttl);
+    buf.push(match config.duplicate_policy {
+        DuplicatePolicy::Keep => 0,
+        DuplicatePolicy::KeepFirst => 1,
+        DuplicatePolicy::KeepLast => 2,
+        DuplicatePolicy::Sum => 3,
+        DuplicatePolicy::Max => 4,
+    });
+}
+fn encode_cardinality_limits(buf: &mut Vec<u8>, limits: &CardinalityLimits) {
+    encode_option_u64(buf, limits.max_keys.map(|n| n as u64));
+    encode_u64(buf, limits.prefixes.len() as u64);
+    for (prefix, max_keys) in &limits.prefixes {
+        encode_key(buf, prefix);
+        encode_u64(buf, *max_keys as u64);
+    }
+    match &limits.admission {
+        Admission::Deny => buf.push(0),
+        Admission::Overflow(key) => {
+            buf.push(1);
+            encode_key(buf, key);
+        }
+    }
+}
+
+struct SettingsReader<'a, R> {
+    rdr: &'a mut R,
+    from: TimeUnit,
+    to: TimeUnit,
+}
+impl<R> SettingsReader<'_, R>
+where
+    R: AsyncRead + Unpin,
+{
+    async fn option_u64(&mut self) -> io::Result<Option<u64>> {
+        Ok(match self.rdr.read_u8().await? {
+            0 => None,
+            _ => Some(self.rdr.read_u64().await?),
+        })
+    }
+    async fn string(&mut self) -> io::Result<String> {
+        let mut s = String::new();
+        decode_key(self.rdr, &mut s).await?;
+        Ok(s)
+    }
+    async fn key_pattern(&mut self) -> io::Result<KeyPattern> {
+        Ok(match self.rdr.read_u8().await? {
+            0 => KeyPattern::Exact(self.string().await?),
+            1 => KeyPattern::Prefix(self.string().await?),
+            2 => KeyPattern::Glob(self.string().await?),
+            3 => {
+                let re = regex::Regex::new(&self.string().await?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                KeyPattern::Regex(re)
+            }
+            _ => return Err(invalid_data("unknown key pattern")),
+        })
+    }
+    async fn queue_config(&mut self) -> io::Result<MetricQueueConfig> {
+        let late_policy = match self.rdr.read_u8().await? {
+            0 => LatePolicy::Append,
+            1 => {
+                let tolerance = self.rdr.read_u64().await?;
+                let tolerance = match tolerance {
+                    u64::MAX => tolerance,
+                    _ => self.from.convert(tolerance, self.to),
+                };
+                LatePolicy::Insert { tolerance }
+            }
+            2 => LatePolicy::Drop,
+            3 => LatePolicy::Reject,
+            _ => return Err(invalid_data("unknown late policy")),
+        };
+        let max_age = self.option_u64().await?.map(Duration::from_nanos);
+        let mut rollups = vec![];
+        for _ in 0..self.rdr.read_u64().await? {
+            let step = self.from.convert(self.rdr.read_u64().await?, self.to);
+            let capacity = self.rdr.read_u64().await? as usize;
+            rollups.push(RollupTier { step, capacity });
+        }
+        let idle_ttl = self.option_u64().await?.map(Duration::from_nanos);
+        let duplicate_policy = match self.rdr.read_u8().await? {
+            0 => DuplicatePolicy::Keep,
+            1 => DuplicatePolicy::KeepFirst,
+            2 => DuplicatePolicy::KeepLast,
+            3 => DuplicatePolicy::Sum,
+            4 => DuplicatePolicy::Max,
+            _ => return Err(invalid_data("unknown duplicate policy")),
+        };
+        Ok(MetricQueueConfig {
+            late_policy,
+            max_age,
+            rollups,
+            idle_ttl,
+            duplicate_policy,
+        })
+    }
+    async fn cardinality_limits(&mut self) -> io::Result<CardinalityLimits> {
+        let max_keys = self.option_u64().await?.map(|n| n as usize);
+        let mut prefixes = vec![];
+        for _ in 0..self.rdr.read_u64().await? {
+            let prefix = self.string().await?;
+            let max_keys = self.rdr.read_u64().await? as usize;
+            prefixes.push((prefix, max_keys));
+        }
+        let admission = match self.rdr.read_u8().await? {
+            0 => Admission::Deny,
+            1 => Admission::Overflow(self.string().await?),
+            _ => return Err(invalid_data("unknown admission")),
+        };
+        Ok(CardinalityLimits {
+            max_keys,
+            prefixes,
+            admission,
+        })
+    }
+}
+
+/// Returns the key, the time the raw samples are evicted until, the step and the buckets, all in the time unit `to`
+#[allow(clippy::type_complexity)]
+fn decode_rollup_record(
+    mut payload: &[u8],
+    from: TimeUnit,
+    to: TimeUnit,
+) -> io::Result<(MetricKey, Option<Time>, Time, VecDeque<RollupBucket>)> {
+    let rdr = &mut payload;
+    let mut u16_buf = [0; 2];
+    let mut u64_buf = [0; 8];
+    Read::read_exact(rdr, &mut u16_buf)?;
+    let mut key = vec![0; usize::from(u16::from_be_bytes(u16_buf))];
+    Read::read_exact(rdr, &mut key)?;
+    let key = String::from_utf8(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut tag = [0; 1];
+    Read::read_exact(rdr, &mut tag)?;
+    let evicted_until = match tag[0] {
+        0 => None,
+        _ => {
+            Read::read_exact(rdr, &mut u64_buf)?;
+            Some(from.convert(u64::from_be_bytes(u64_buf), to))
+        }
+    };
+    Read::read_exact(rdr, &mut u64_buf)?;
+    let step = from.convert(u64::from_be_bytes(u64_buf), to);
+    Read::read_exact(rdr, &mut u16_buf)?;
+    let mut buckets = VecDeque::new();
+    for _ in 0..u16::from_be_bytes(u16_buf) {
+        let mut bucket = [0; ROLLUP_BUCKET_SIZE];
+        Read::read_exact(rdr, &mut bucket)?;
+        buckets.push_back(RollupBucket::decode(bucket).convert(from, to));
+    }
+    Ok((key, evicted_until, step, buckets))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        consumer::MetricQueueConfig,
+        wal::{FsyncPolicy, WriteAheadLog},
+        Sample,
+    };
+
+    use super::*;
+
+    fn sample(time: Time) -> Sample {
+        Sample {
+            time,
+            value: time as f64,
+        }
+    }
+    fn times(consumer: &MetricConsumer, key: &str) -> Vec<Time> {
+        let span = consumer.metrics()[key].span(..);
+        span.iter().map(|sample| sample.time).collect()
+    }
+
+    #[tokio::test]
+    async fn test_restore_rollups() {
+        let dir = std::env::temp_dir().join(format!("metrics-snapshot-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let key = "a".to_string();
+        let mut consumer = MetricConsumer::new(8);
+        consumer.set_default_queue_config(MetricQueueConfig {
+            rollups: vec![RollupTier {
+                step: 10,
+                capacity: 16,
+            }],
+            ..Default::default()
+        });
+        for time in 0..40 {
+            consumer.push(&key)(sample(time)).unwrap();
+        }
+        let mut buf = vec![];
+        write_snapshot(&consumer, &mut buf);
+
+        let open = || WriteAheadLog::open(&dir, TimeUnit::Millis, FsyncPolicy::Always).unwrap();
+        let mut restored = MetricConsumer::new(8);
+        restored.set_wal(Some(open()));
+        restored.push(&"b".into())(sample(0)).unwrap();
+        read_snapshot(&mut &buf[..], &mut restored, RestoreMode::Replace)
+            .await
+            .unwrap();
+        let (queue, other) = (&consumer.metrics()[&key], &restored.metrics()[&key]);
+        assert_eq!(other.evicted_until(), queue.evicted_until());
+        assert_eq!(other.rollups()[0].buckets(), queue.rollups()[0].buckets());
+        assert_eq!(times(&restored, &key), times(&consumer, &key));
+        assert!(!restored.metrics().contains_key("b"));
+        drop(restored);
+
+        // The replaced key stays deleted on replay
+        let mut replayed = MetricConsumer::new(8);
+        replayed.set_wal(Some(open()));
+        replayed.replay_wal().await.unwrap();
+        assert!(!replayed.metrics().contains_key("b"));
+        assert_eq!(times(&replayed, &key), times(&consumer, &key));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1252 @@
+//! Append-only on-disk storage
+//!
+//! Each key owns a directory of segment files named by increasing ids.
+//! The directory is named by the hex-encoded key, or by a hash of a key too long for that with the key kept in a file inside.
+//! A segment is a header followed by blocks of samples compressed with delta-encoded times and XOR-encoded values.
+//! Only the segment with the largest id is appended to; the others are sealed and subject to compaction.
+//! Blocks are numbered by a sequence that grows with every flush of the series.
+//! Deletions are recorded as tombstones that hide the samples of the blocks numbered before them until compaction drops those samples for good.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    codec::{decode_time_unit, encode_time_unit},
+    consumer::{TimeSeries, TimeSeriesSpan},
+    MetricKey, Sample, Time, TimeUnit,
+};
+
+const SEGMENT_MAGIC: [u8; 4] = *b"MSEG";
+const SEGMENT_VERSION: u8 = 3;
+/// Magic, version, time unit and the id of the first segment it supersedes
+const SEGMENT_HEADER_SIZE: usize = 4 + 1 + 1 + 8;
+/// Payload length, sample count, first time, last time, sequence and CRC
+const BLOCK_HEADER_SIZE: usize = 4 + 4 + 8 + 8 + 8 + 4;
+const SEGMENT_EXTENSION: &str = "seg";
+const TMP_EXTENSION: &str = "tmp";
+const TOMBSTONES_FILE: &str = "tombstones";
+const KEY_FILE: &str = "key";
+/// Leads the directory names of hashed keys, which is never part of a hex-encoded one
+const HASHED_DIR_PREFIX: char = '~';
+/// Hex-encoded keys longer than this would exceed the common file name limit of 255 bytes
+const MAX_HEX_KEY_LEN: usize = 120;
+/// CRC, time unit, sequence, start and end
+const TOMBSTONE_SIZE: usize = 4 + 1 + 8 + 8 + 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskStoreConfig {
+    /// Maximum number of samples per block
+    pub block_samples: usize,
+    /// A segment is sealed once it grows past this size
+    pub segment_bytes: u64,
+}
+impl Default for DiskStoreConfig {
+    fn default() -> Self {
+        Self {
+            block_samples: 1024,
+            segment_bytes: 1 << 24,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DiskStore {
+    dir: PathBuf,
+    time_unit: TimeUnit,
+    config: DiskStoreConfig,
+    series: HashMap<MetricKey, DiskSeries>,
+}
+impl DiskStore {
+    /// Recovers from an unclean shutdown by dropping torn blocks and leftovers of interrupted compactions
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        time_unit: TimeUnit,
+        config: DiskStoreConfig,
+    ) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut series = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            let key = match name.starts_with(HASHED_DIR_PREFIX) {
+                // Not there if the directory was torn while being created, and then it holds no segments either
+                true => match fs::read_to_string(entry.path().join(KEY_FILE)) {
+                    Ok(key) => Some(key),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e),
+                },
+                false => decode_dir_name(&name),
+            };
+            let Some(key) = key else {
+                continue;
+            };
+            series.insert(key.clone(), DiskSeries::open(key, entry.path(), time_unit)?);
+        }
+        Ok(Self {
+            dir,
+            time_unit,
+            config,
+            series,
+        })
+    }
+
+    pub fn time_unit(&self) -> TimeUnit {
+        self.time_unit
+    }
+    pub fn keys(&self) -> impl Iterator<Item = &MetricKey> {
+        self.series.keys()
+    }
+    pub fn series(&self, key: &str) -> Option<&DiskSeries> {
+        self.series.get(key)
+    }
+
+    /// Buffers the sample in memory until [`DiskStore::flush`]
+    pub fn push(&mut self, key: &MetricKey, sample: Sample) {
+        let series = match self.series.get_mut(key) {
+            Some(series) => series,
+            None => {
+                let dir = self.new_dir(key);
+                self.series.entry(key.clone()).or_insert(DiskSeries::new(
+                    key.clone(),
+                    dir,
+                    self.time_unit,
+                ))
+            }
+        };
+        series.pending.push(sample);
+    }
+    /// Buffers the sample in memory in place of the one stored at its time until [`DiskStore::flush`]
+    pub fn replace(&mut self, key: &MetricKey, sample: Sample) {
+        let Some(series) = self.series.get_mut(key) else {
+            self.push(key, sample);
+            return;
+        };
+        series.pending.retain(|pending| pending.time != sample.time);
+        series.pending.push(sample);
+        series.replaced.push(sample.time);
+    }
+    fn new_dir(&self, key: &str) -> PathBuf {
+        if key.len() <= MAX_HEX_KEY_LEN {
+            return self.dir.join(encode_dir_name(key));
+        }
+        // Tells keys of colliding hashes apart
+        let hash = fnv1a(key.as_bytes());
+        (0..)
+            .map(|n| {
+                let name = format!("{HASHED_DIR_PREFIX}{hash:016x}-{n}");
+                self.dir.join(name)
+            })
+            .find(|dir| self.series.values().all(|series| series.dir != *dir) && !dir.exists())
+            .unwrap()
+    }
+    /// Writes the buffered samples to disk and syncs them
+    ///
+    /// A series failing to do so keeps its samples buffered for the next try and does not hold back the others.
+    pub fn flush(&mut self) -> Result<(), FlushError> {
+        let mut failures = vec![];
+        for (key, series) in &mut self.series {
+            if let Err(e) = series.flush(&self.config) {
+                failures.push((key.clone(), e));
+            }
+        }
+        if !failures.is_empty() {
+            return Err(FlushError { failures });
+        }
+        Ok(())
+    }
+    /// Hides the stored samples of `key` within `range`
+    pub fn delete(&mut self, key: &str, range: RangeInclusive<Time>) -> io::Result<()> {
+        match self.series.get_mut(key) {
+            Some(series) => series.delete(range),
+            None => Ok(()),
+        }
+    }
+    /// Drops samples older than the cutoff of their keys and merges small sealed segments
+    ///
+    /// Does all of [`DiskStore::plan_compaction`], [`Compaction::run`] and [`DiskStore::finish_compaction`].
+    pub fn compact(&mut self, cutoff: impl Fn(&str) -> Option<Time>) -> io::Result<()> {
+        let mut compaction = self.plan_compaction(cutoff)?;
+        let res = compaction.run();
+        self.finish_compaction(compaction)?;
+        res
+    }
+    /// Removes the sealed segments past the cutoff of their keys right away and picks a bounded run of the others to rewrite per series
+    ///
+    /// A series is left out while a compaction planned before is not finished, so the plan has to be handed to [`DiskStore::finish_compaction`] whether it has run or not.
+    pub fn plan_compaction(
+        &mut self,
+        cutoff: impl Fn(&str) -> Option<Time>,
+    ) -> io::Result<Compaction> {
+        let mut jobs = vec![];
+        for (key, series) in &mut self.series {
+            if let Some(job) = series.plan_compaction(&self.config, cutoff(key))? {
+                jobs.push(job);
+            }
+        }
+        Ok(Compaction {
+            time_unit: self.time_unit,
+            block_samples: self.config.block_samples,
+            jobs,
+        })
+    }
+    /// Puts the segments written by [`Compaction::run`] in place of those they were merged from
+    ///
+    /// Those not written, or whose series has changed its sealed segments since, are discarded.
+    pub fn finish_compaction(&mut self, compaction: Compaction) -> io::Result<()> {
+        for job in compaction.jobs {
+            if let Some(series) = self.series.get_mut(&job.key) {
+                series.finish_compaction(job)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Work picked by [`DiskStore::plan_compaction`], which needs no access to the store to be run
+#[derive(Debug)]
+pub struct Compaction {
+    time_unit: TimeUnit,
+    block_samples: usize,
+    jobs: Vec<CompactionJob>,
+}
+impl Compaction {
+    /// Reads the planned segments and writes each run of them merged aside
+    ///
+    /// Stops at the first failure; what is written so far can still be finished.
+    pub fn run(&mut self) -> io::Result<()> {
+        for job in &mut self.jobs {
+            job.run(self.time_unit, self.block_samples)?;
+            job.written = true;
+        }
+        Ok(())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+}
+
+#[derive(Debug)]
+struct CompactionJob {
+    key: MetricKey,
+    dir: PathBuf,
+    /// Consecutive sealed segments as of planning
+    segments: Vec<Segment>,
+    /// Applied to the merged samples
+    tombstones: Vec<Tombstone>,
+    cutoff: Option<Time>,
+    /// Of the merged blocks, past those of the tombstones applied to them and before those of the later ones
+    seq: u64,
+    written: bool,
+}
+impl CompactionJob {
+    fn run(&self, time_unit: TimeUnit, block_samples: usize) -> io::Result<()> {
+        let mut samples = vec![];
+        for segment in &self.segments {
+            for block in &segment.blocks {
+                let last_time = segment.time_unit.convert(block.last_time, time_unit);
+                if self.cutoff.is_some_and(|cutoff| last_time < cutoff) {
+                    continue;
+                }
+                let block_samples = read_block(&self.dir, segment, block, time_unit)?;
+                let block_samples = block_samples.into_iter().filter(|sample| {
+                    self.cutoff.is_none_or(|cutoff| cutoff <= sample.time)
+                        && !hidden(&self.tombstones, block, sample.time)
+                });
+                samples.extend(block_samples);
+            }
+        }
+        samples.sort_by_key(|sample| sample.time);
+
+        let (first, last) = (&self.segments[0], self.segments.last().unwrap());
+        let tmp_path = segment_path(&self.dir, last.id).with_extension(TMP_EXTENSION);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&encode_segment_header(first.first_id, time_unit))?;
+        let mut buf = vec![];
+        for samples in samples.chunks(block_samples.max(1)) {
+            encode_block(samples, self.seq, &mut buf);
+            file.write_all(&buf)?;
+        }
+        file.sync_all()
+    }
+}
+
+/// The series [`DiskStore::flush`] failed to write, each with why
+#[derive(Debug)]
+pub struct FlushError {
+    pub failures: Vec<(MetricKey, io::Error)>,
+}
+impl core::fmt::Display for FlushError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let (key, e) = &self.failures[0];
+        write!(f, "failed to flush `{key}`: {e}")?;
+        match self.failures.len() - 1 {
+            0 => Ok(()),
+            n => write!(f, " and {n} more series"),
+        }
+    }
+}
+impl std::error::Error for FlushError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.failures[0].1)
+    }
+}
+impl From<FlushError> for io::Error {
+    fn from(e: FlushError) -> Self {
+        io::Error::other(e)
+    }
+}
+
+#[derive(Debug)]
+pub struct DiskSeries {
+    key: MetricKey,
+    dir: PathBuf,
+    time_unit: TimeUnit,
+    /// In increasing order of ids
+    segments: Vec<Segment>,
+    pending: Vec<Sample>,
+    /// Times of the pending samples that hide the stored ones once flushed
+    replaced: Vec<Time>,
+    tombstones: Vec<Tombstone>,
+    /// Sequence of the next block, past those of all the blocks and tombstones so far
+    next_seq: u64,
+    /// A planned compaction is not finished yet
+    compacting: bool,
+}
+impl DiskSeries {
+    fn new(key: MetricKey, dir: PathBuf, time_unit: TimeUnit) -> Self {
+        Self {
+            key,
+            dir,
+            time_unit,
+            segments: vec![],
+            pending: vec![],
+            replaced: vec![],
+            tombstones: vec![],
+            next_seq: 0,
+            compacting: false,
+        }
+    }
+    fn open(key: MetricKey, dir: PathBuf, time_unit: TimeUnit) -> io::Result<Self> {
+        let mut ids = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some(TMP_EXTENSION) => fs::remove_file(&path)?,
+                Some(SEGMENT_EXTENSION) => {
+                    let id = path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str()?.parse().ok());
+                    if let Some(id) = id {
+                        ids.push(id);
+                    }
+                }
+                _ => (),
+            }
+        }
+        ids.sort_unstable();
+        let mut segments: Vec<Segment> = vec![];
+        for id in ids {
+            // Torn while being created
+            if fs::metadata(segment_path(&dir, id))?.len() < SEGMENT_HEADER_SIZE as u64 {
+                fs::remove_file(segment_path(&dir, id))?;
+                continue;
+            }
+            let segment = Segment::open(&dir, id)?;
+            // A compacted segment replaces the ones it was merged from
+            while segments
+                .last()
+                .is_some_and(|last| segment.first_id <= last.id)
+            {
+                let stale = segments.pop().unwrap();
+                fs::remove_file(segment_path(&dir, stale.id))?;
+            }
+            segments.push(segment);
+        }
+        let tombstones = read_tombstones(&dir, time_unit)?;
+        // Blocks written after the latest tombstone might have been torn off
+        let blocks = segments.iter().flat_map(|segment| &segment.blocks);
+        let next_seq = blocks
+            .map(|block| block.seq + 1)
+            .chain(tombstones.iter().map(|tombstone| tombstone.seq))
+            .max()
+            .unwrap_or(0);
+        Ok(Self {
+            key,
+            dir,
+            time_unit,
+            segments,
+            pending: vec![],
+            replaced: vec![],
+            tombstones,
+            next_seq,
+            compacting: false,
+        })
+    }
+
+    fn delete(&mut self, range: RangeInclusive<Time>) -> io::Result<()> {
+        self.pending.retain(|sample| !range.contains(&sample.time));
+        self.replaced.retain(|time| !range.contains(time));
+        self.write_tombstones(&[range])
+    }
+    /// Hides the samples of the blocks written so far within `ranges`
+    fn write_tombstones(&mut self, ranges: &[RangeInclusive<Time>]) -> io::Result<()> {
+        if self.segments.is_empty() || ranges.is_empty() {
+            return Ok(());
+        }
+        let tombstones = ranges.iter().map(|range| Tombstone {
+            seq: self.next_seq,
+            start: *range.start(),
+            end: *range.end(),
+        });
+        let tombstones = tombstones.collect::<Vec<_>>();
+        let mut buf = vec![];
+        for tombstone in &tombstones {
+            buf.extend(encode_tombstone(tombstone, self.time_unit));
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.dir.join(TOMBSTONES_FILE))?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        self.tombstones.extend(tombstones);
+        Ok(())
+    }
+    /// Whether the sample at `time` in `block` is hidden by a tombstone
+    fn deleted(&self, block: &BlockMeta, time: Time) -> bool {
+        hidden(&self.tombstones, block, time)
+    }
+
+    fn flush(&mut self, config: &DiskStoreConfig) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        // The replacements land in blocks of later sequences than the tombstones
+        let replaced = self.replaced.iter().map(|&time| time..=time);
+        self.write_tombstones(&replaced.collect::<Vec<_>>())?;
+        self.replaced.clear();
+        self.pending.sort_by_key(|sample| sample.time);
+        let active = self
+            .segments
+            .last()
+            .filter(|segment| segment.len < config.segment_bytes);
+        if active.is_none() {
+            self.create_dir()?;
+            let id = self
+                .segments
+                .last()
+                .map(|segment| segment.id + 1)
+                .unwrap_or(0);
+            let segment = Segment::create(&self.dir, id, self.time_unit)?;
+            self.segments.push(segment);
+            sync_dir(&self.dir)?;
+        }
+        let segment = self.segments.last_mut().unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&self.dir, segment.id))?;
+        // Left over by a failed flush whose cleanup failed as well
+        if file.metadata()?.len() != segment.len {
+            file.set_len(segment.len)?;
+        }
+        let (len, blocks, seq) = (segment.len, segment.blocks.len(), self.next_seq);
+        if let Err(e) = write_blocks(
+            &mut file,
+            segment,
+            &self.pending,
+            &mut self.next_seq,
+            config,
+        ) {
+            // Cut the blocks written so far so that the retry does not write them twice
+            segment.len = len;
+            segment.blocks.truncate(blocks);
+            self.next_seq = seq;
+            let _ = file.set_len(len);
+            return Err(e);
+        }
+        self.pending.clear();
+        Ok(())
+    }
+    fn create_dir(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let hashed = self
+            .dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(HASHED_DIR_PREFIX));
+        let path = self.dir.join(KEY_FILE);
+        if hashed && !path.exists() {
+            let tmp_path = path.with_extension(TMP_EXTENSION);
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(self.key.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+        }
+        sync_dir(&self.dir)?;
+        sync_dir(self.dir.parent().unwrap())
+    }
+
+    fn plan_compaction(
+        &mut self,
+        config: &DiskStoreConfig,
+        cutoff: Option<Time>,
+    ) -> io::Result<Option<CompactionJob>> {
+        if self.compacting || self.segments.len() < 2 {
+            return Ok(None);
+        }
+        let time_unit = self.time_unit;
+        let last_time = |segment: &Segment, block: &BlockMeta| {
+            segment.time_unit.convert(block.last_time, time_unit)
+        };
+        let first_time = |segment: &Segment, block: &BlockMeta| {
+            segment.time_unit.convert(block.first_time, time_unit)
+        };
+
+        // Wholly expired ones go without being read
+        if let Some(cutoff) = cutoff {
+            let sealed = &self.segments[..self.segments.len() - 1];
+            let expired = sealed
+                .iter()
+                .filter(|segment| {
+                    let mut blocks = segment.blocks.iter();
+                    blocks.all(|block| last_time(segment, block) < cutoff)
+                })
+                .map(|segment| segment.id)
+                .collect::<Vec<_>>();
+            if !expired.is_empty() {
+                for &id in &expired {
+                    fs::remove_file(segment_path(&self.dir, id))?;
+                }
+                sync_dir(&self.dir)?;
+                self.segments
+                    .retain(|segment| !expired.contains(&segment.id));
+                self.prune_tombstones()?;
+            }
+        }
+
+        let (_active, sealed) = self.segments.split_last().unwrap();
+        let expiring = |segment: &Segment| {
+            let mut blocks = segment.blocks.iter();
+            blocks.any(|block| cutoff.is_some_and(|cutoff| first_time(segment, block) < cutoff))
+        };
+        let deleting = |segment: &Segment| {
+            segment.blocks.iter().any(|block| {
+                let (first, last) = (first_time(segment, block), last_time(segment, block));
+                self.tombstones
+                    .iter()
+                    .any(|tombstone| tombstone.hides(block, first, last))
+            })
+        };
+        let small = |segment: &Segment| segment.len < config.segment_bytes / 2;
+        // Bounded by the segment size, or by the one segment that exceeds it
+        let runs = (0..sealed.len()).map(|start| {
+            let mut len = sealed[start].len;
+            let end = (start + 1..sealed.len())
+                .find(|&end| {
+                    len += sealed[end].len;
+                    config.segment_bytes < len
+                })
+                .unwrap_or(sealed.len());
+            &sealed[start..end]
+        });
+        let mut runs = runs.filter(|run| {
+            let work = run
+                .iter()
+                .any(|segment| expiring(segment) || deleting(segment));
+            work || 2 <= run.iter().filter(|segment| small(segment)).count()
+        });
+        let Some(run) = runs.next() else {
+            return Ok(None);
+        };
+        let job = CompactionJob {
+            key: self.key.clone(),
+            dir: self.dir.clone(),
+            segments: run.to_vec(),
+            tombstones: self.tombstones.clone(),
+            cutoff,
+            seq: self.next_seq,
+            written: false,
+        };
+        self.next_seq += 1;
+        self.compacting = true;
+        Ok(Some(job))
+    }
+    fn finish_compaction(&mut self, job: CompactionJob) -> io::Result<()> {
+        self.compacting = false;
+        let last_id = job.segments.last().unwrap().id;
+        let tmp_path = segment_path(&self.dir, last_id).with_extension(TMP_EXTENSION);
+        let start = self
+            .segments
+            .iter()
+            .position(|segment| segment.id == job.segments[0].id);
+        let unchanged = start.is_some_and(|start| {
+            let segments = self.segments.get(start..start + job.segments.len());
+            segments.is_some_and(|segments| {
+                let mut pairs = segments.iter().zip(&job.segments);
+                pairs.all(|(a, b)| a.id == b.id && a.len == b.len)
+            })
+        });
+        let (true, true, Some(start)) = (job.written, unchanged, start) else {
+            return match fs::remove_file(&tmp_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        };
+
+        // Renamed over the last of the run, so that a crash leaves either the old segments or the new one
+        fs::rename(&tmp_path, segment_path(&self.dir, last_id))?;
+        sync_dir(&self.dir)?;
+        for segment in &job.segments {
+            if segment.id != last_id {
+                fs::remove_file(segment_path(&self.dir, segment.id))?;
+            }
+        }
+        let merged = Segment::open(&self.dir, last_id)?;
+        self.segments
+            .splice(start..start + job.segments.len(), [merged]);
+        self.prune_tombstones()
+    }
+    /// Drops the tombstones that no longer hide any stored sample
+    fn prune_tombstones(&mut self) -> io::Result<()> {
+        let time_unit = self.time_unit;
+        let len = self.tombstones.len();
+        let segments = &self.segments;
+        self.tombstones.retain(|tombstone| {
+            segments.iter().any(|segment| {
+                segment.blocks.iter().any(|block| {
+                    let first = segment.time_unit.convert(block.first_time, time_unit);
+                    let last = segment.time_unit.convert(block.last_time, time_unit);
+                    tombstone.hides(block, first, last)
+                })
+            })
+        });
+        if self.tombstones.len() == len {
+            return Ok(());
+        }
+        let path = self.dir.join(TOMBSTONES_FILE);
+        let tmp_path = path.with_extension(TMP_EXTENSION);
+        let mut file = File::create(&tmp_path)?;
+        for tombstone in &self.tombstones {
+            file.write_all(&encode_tombstone(tombstone, self.time_unit))?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        sync_dir(&self.dir)
+    }
+
+    fn read_block(&self, segment: &Segment, block: &BlockMeta) -> io::Result<Vec<Sample>> {
+        read_block(&self.dir, segment, block, self.time_unit)
+    }
+
+    /// Includes the samples not yet flushed
+    pub fn read(&self, range: impl core::ops::RangeBounds<Time>) -> io::Result<Vec<Sample>> {
+        let mut samples = vec![];
+        for segment in &self.segments {
+            for block in &segment.blocks {
+                let first = segment.time_unit.convert(block.first_time, self.time_unit);
+                let last = segment.time_unit.convert(block.last_time, self.time_unit);
+                if !overlaps(&range, first, last) {
+                    continue;
+                }
+                let block_samples = self.read_block(segment, block)?;
+                samples.extend(block_samples.into_iter().filter(|sample| {
+                    range.contains(&sample.time)
+                        && !self.deleted(block, sample.time)
+                        && !self.replaced.contains(&sample.time)
+                }));
+            }
+        }
+        let pending = self
+            .pending
+            .iter()
+            .filter(|sample| range.contains(&sample.time));
+        samples.extend(pending);
+        samples.sort_by_key(|sample| sample.time);
+        Ok(samples)
+    }
+}
+impl TimeSeries for DiskSeries {
+    /// [`None`] on I/O errors
+    fn span(&self, time_range: impl core::ops::RangeBounds<Time>) -> Option<TimeSeriesSpan<'_>> {
+        let samples = self.read(time_range).ok()?;
+        let count = samples.len();
+        Some(TimeSeriesSpan {
+            samples: Box::new(samples.into_iter()),
+            count,
+        })
+    }
+}
+
+fn write_blocks(
+    file: &mut File,
+    segment: &mut Segment,
+    samples: &[Sample],
+    next_seq: &mut u64,
+    config: &DiskStoreConfig,
+) -> io::Result<()> {
+    let mut buf = vec![];
+    for samples in samples.chunks(config.block_samples.max(1)) {
+        let block = encode_block(samples, *next_seq, &mut buf);
+        *next_seq += 1;
+        file.write_all(&buf)?;
+        segment.blocks.push(BlockMeta {
+            offset: segment.len,
+            ..block
+        });
+        segment.len += buf.len() as u64;
+    }
+    file.sync_data()
+}
+
+fn read_block(
+    dir: &Path,
+    segment: &Segment,
+    block: &BlockMeta,
+    time_unit: TimeUnit,
+) -> io::Result<Vec<Sample>> {
+    let mut file = File::open(segment_path(dir, segment.id))?;
+    file.seek(SeekFrom::Start(block.offset + BLOCK_HEADER_SIZE as u64))?;
+    let mut payload = vec![0; block.len as usize];
+    file.read_exact(&mut payload)?;
+    let mut samples = decode_block_payload(&payload, block.count)?;
+    if segment.time_unit != time_unit {
+        for sample in &mut samples {
+            sample.time = segment.time_unit.convert(sample.time, time_unit);
+        }
+    }
+    Ok(samples)
+}
+/// Whether the sample at `time` in `block` is hidden by any of `tombstones`
+fn hidden(tombstones: &[Tombstone], block: &BlockMeta, time: Time) -> bool {
+    tombstones
+        .iter()
+        .any(|tombstone| block.seq < tombstone.seq && tombstone.contains(time))
+}
+
+fn overlaps(range: &impl core::ops::RangeBounds<Time>, first: Time, last: Time) -> bool {
+    use core::ops::Bound;
+    let after_start = match range.start_bound() {
+        Bound::Included(&start) => start <= last,
+        Bound::Excluded(&start) => start < last,
+        Bound::Unbounded => true,
+    };
+    let before_end = match range.end_bound() {
+        Bound::Included(&end) => first <= end,
+        Bound::Excluded(&end) => first < end,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    id: u64,
+    /// Smallest id of the segments this one was compacted from
+    first_id: u64,
+    time_unit: TimeUnit,
+    /// Bytes of the valid prefix of the file
+    len: u64,
+    blocks: Vec<BlockMeta>,
+}
+impl Segment {
+    fn create(dir: &Path, id: u64, time_unit: TimeUnit) -> io::Result<Self> {
+        let mut file = File::create(segment_path(dir, id))?;
+        file.write_all(&encode_segment_header(id, time_unit))?;
+        file.sync_all()?;
+        Ok(Self {
+            id,
+            first_id: id,
+            time_unit,
+            len: SEGMENT_HEADER_SIZE as u64,
+            blocks: vec![],
+        })
+    }
+    /// Truncates the file after the last intact block
+    fn open(dir: &Path, id: u64) -> io::Result<Self> {
+        let path = segment_path(dir, id);
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut header = [0; SEGMENT_HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        let (first_id, time_unit) = decode_segment_header(header)?;
+        let file_len = file.metadata()?.len();
+        let mut rdr = io::BufReader::new(&mut file);
+        let mut len = SEGMENT_HEADER_SIZE as u64;
+        let mut blocks = vec![];
+        let mut payload = vec![];
+        loop {
+            let mut header = [0; BLOCK_HEADER_SIZE];
+            if rdr.read_exact(&mut header).is_err() {
+                break;
+            }
+            let (block, crc) = decode_block_header(header);
+            let end = len + BLOCK_HEADER_SIZE as u64 + u64::from(block.len);
+            if file_len < end {
+                break;
+            }
+            payload.resize(block.len as usize, 0);
+            rdr.read_exact(&mut payload)?;
+            if block_crc(&header, &payload) != crc {
+                break;
+            }
+            blocks.push(BlockMeta {
+                offset: len,
+                ..block
+            });
+            len = end;
+        }
+        drop(rdr);
+        if len < file_len {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        Ok(Self {
+            id,
+            first_id,
+            time_unit,
+            len,
+            blocks,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockMeta {
+    /// Offset of the block header in the segment file
+    offset: u64,
+    /// Bytes of the payload
+    len: u32,
+    count: u32,
+    first_time: Time,
+    last_time: Time,
+    /// Position among the flushes of the series; a compacted block takes the one reserved when its compaction was planned
+    seq: u64,
+}
+
+/// Hides the samples within `start..=end` of the blocks flushed before it
+///
+/// Unlike a position in the segment files, the sequence stays put when a crash tears off blocks that are then written again.
+#[derive(Debug, Clone, Copy)]
+struct Tombstone {
+    /// Sequence of the next block at the time of the deletion
+    seq: u64,
+    start: Time,
+    end: Time,
+}
+impl Tombstone {
+    fn contains(&self, time: Time) -> bool {
+        self.start <= time && time <= self.end
+    }
+    /// Whether it hides any sample of `block`, which spans `first..=last`
+    fn hides(&self, block: &BlockMeta, first: Time, last: Time) -> bool {
+        block.seq < self.seq && self.start <= last && first <= self.end
+    }
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id:020}.{SEGMENT_EXTENSION}"))
+}
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// 64-bit FNV-1a, which unlike [`std::hash::DefaultHasher`] stays the same across builds
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+/// Keys are hex-encoded so that any key makes a valid file name
+fn encode_dir_name(key: &str) -> String {
+    key.bytes().map(|byte| format!("{byte:02x}")).collect()
+}
+fn decode_dir_name(name: &str) -> Option<MetricKey> {
+    if !name.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+fn encode_segment_header(first_id: u64, time_unit: TimeUnit) -> [u8; SEGMENT_HEADER_SIZE] {
+    let mut buf = [0; SEGMENT_HEADER_SIZE];
+    let mut wtr = io::Cursor::new(&mut buf[..]);
+    wtr.write_all(&SEGMENT_MAGIC).unwrap();
+    wtr.write_all(&[SEGMENT_VERSION]).unwrap();
+    wtr.write_all(&encode_time_unit(time_unit)).unwrap();
+    wtr.write_all(&first_id.to_be_bytes()).unwrap();
+    buf
+}
+fn decode_segment_header(buf: [u8; SEGMENT_HEADER_SIZE]) -> io::Result<(u64, TimeUnit)> {
+    if buf[..4] != SEGMENT_MAGIC || buf[4] != SEGMENT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a segment file",
+        ));
+    }
+    let time_unit = decode_time_unit([buf[5]])?;
+    let first_id = u64::from_be_bytes(buf[6..14].try_into().unwrap());
+    Ok((first_id, time_unit))
+}
+
+fn encode_tombstone(tombstone: &Tombstone, time_unit: TimeUnit) -> [u8; TOMBSTONE_SIZE] {
+    let mut buf = [0; TOMBSTONE_SIZE];
+    let mut wtr = io::Cursor::new(&mut buf[4..]);
+    wtr.write_all(&encode_time_unit(time_unit)).unwrap();
+    wtr.write_all(&tombstone.seq.to_be_bytes()).unwrap();
+    wtr.write_all(&tombstone.start.to_be_bytes()).unwrap();
+    wtr.write_all(&tombstone.end.to_be_bytes()).unwrap();
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_be_bytes());
+    buf
+}
+/// Drops a torn or corrupt tail left by a crash
+fn read_tombstones(dir: &Path, time_unit: TimeUnit) -> io::Result<Vec<Tombstone>> {
+    let path = dir.join(TOMBSTONES_FILE);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut tombstones = vec![];
+    for buf in data.chunks_exact(TOMBSTONE_SIZE) {
+        let crc = u32::from_be_bytes(buf[..4].try_into().unwrap());
+        if crc32fast::hash(&buf[4..]) != crc {
+            break;
+        }
+        let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+        let unit = decode_time_unit([buf[4]])?;
+        let start = unit.convert(u64_at(13), time_unit);
+        let end = match u64_at(21) {
+            Time::MAX => Time::MAX,
+            end => unit.convert(end, time_unit),
+        };
+        tombstones.push(Tombstone {
+            seq: u64_at(5),
+            start,
+            end,
+        });
+    }
+    let valid = (tombstones.len() * TOMBSTONE_SIZE) as u64;
+    if valid < data.len() as u64 {
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(valid)?;
+        file.sync_all()?;
+    }
+    Ok(tombstones)
+}
+
+/// Replaces the content of `buf` with the encoded block of sorted `samples`
+fn encode_block(samples: &[Sample], seq: u64, buf: &mut Vec<u8>) -> BlockMeta {
+    buf.clear();
+    buf.extend([0; BLOCK_HEADER_SIZE]);
+    let mut prev = Sample { time: 0, value: 0. };
+    for sample in samples {
+        encode_varint(buf, sample.time - prev.time);
+        encode_xor(buf, sample.value.to_bits() ^ prev.value.to_bits());
+        prev = *sample;
+    }
+    let block = BlockMeta {
+        offset: 0,
+        len: u32::try_from(buf.len() - BLOCK_HEADER_SIZE).unwrap(),
+        count: u32::try_from(samples.len()).unwrap(),
+        first_time: samples.first().map(|sample| sample.time).unwrap_or(0),
+        last_time: samples.last().map(|sample| sample.time).unwrap_or(0),
+        seq,
+    };
+    let mut header = io::Cursor::new(&mut buf[..BLOCK_HEADER_SIZE]);
+    header.write_all(&block.len.to_be_bytes()).unwrap();
+    header.write_all(&block.count.to_be_bytes()).unwrap();
+    header.write_all(&block.first_time.to_be_bytes()).unwrap();
+    header.write_all(&block.last_time.to_be_bytes()).unwrap();
+    header.write_all(&block.seq.to_be_bytes()).unwrap();
+    let (header, payload) = buf.split_at(BLOCK_HEADER_SIZE);
+    let crc = block_crc(header.try_into().unwrap(), payload);
+    buf[BLOCK_HEADER_SIZE - 4..BLOCK_HEADER_SIZE].copy_from_slice(&crc.to_be_bytes());
+    block
+}
+fn decode_block_header(buf: [u8; BLOCK_HEADER_SIZE]) -> (BlockMeta, u32) {
+    let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+    let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+    let block = BlockMeta {
+        offset: 0,
+        len: u32_at(0),
+        count: u32_at(4),
+        first_time: u64_at(8),
+        last_time: u64_at(16),
+        seq: u64_at(24),
+    };
+    (block, u32_at(32))
+}
+/// Covers the block header except for the CRC itself
+fn block_crc(header: &[u8; BLOCK_HEADER_SIZE], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[..BLOCK_HEADER_SIZE - 4]);
+    hasher.update(payload);
+    hasher.finalize()
+}
+fn decode_block_payload(mut payload: &[u8], count: u32) -> io::Result<Vec<Sample>> {
+    let mut samples = Vec::with_capacity(count as usize);
+    let mut prev = Sample { time: 0, value: 0. };
+    for _ in 0..count {
+        let time = prev.time + decode_varint(&mut payload)?;
+        let value = f64::from_bits(prev.value.to_bits() ^ decode_xor(&mut payload)?);
+        prev = Sample { time, value };
+        samples.push(prev);
+    }
+    Ok(samples)
+}
+
+fn encode_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while 0x80 <= n {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+fn decode_varint(rdr: &mut &[u8]) -> io::Result<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = rdr.split_first().ok_or_else(truncated)?;
+        *rdr = rest;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(truncated())
+}
+/// Only keeps the bytes between the leading and trailing zero bytes, since close values share both their high and low bits
+fn encode_xor(buf: &mut Vec<u8>, xor: u64) {
+    if xor == 0 {
+        buf.push(0xff);
+        return;
+    }
+    let leading = xor.leading_zeros() / 8;
+    let trailing = xor.trailing_zeros() / 8;
+    buf.push(((leading << 4) | trailing) as u8);
+    let bytes = xor.to_be_bytes();
+    buf.extend(&bytes[leading as usize..8 - trailing as usize]);
+}
+fn decode_xor(rdr: &mut &[u8]) -> io::Result<u64> {
+    let (&tag, rest) = rdr.split_first().ok_or_else(truncated)?;
+    *rdr = rest;
+    if tag == 0xff {
+        return Ok(0);
+    }
+    let leading = usize::from(tag >> 4);
+    let trailing = usize::from(tag & 0x0f);
+    let len = 8_usize
+        .checked_sub(leading + trailing)
+        .ok_or_else(truncated)?;
+    if rdr.len() < len {
+        return Err(truncated());
+    }
+    let mut bytes = [0; 8];
+    bytes[leading..8 - trailing].copy_from_slice(&rdr[..len]);
+    *rdr = &rdr[len..];
+    Ok(u64::from_be_bytes(bytes))
+}
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "truncated block")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("metrics-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+    fn sample(time: Time) -> Sample {
+        Sample {
+            time,
+            value: time as f64,
+        }
+    }
+
+    #[test]
+    fn test_long_key() {
+        let dir = temp_dir("long-key");
+        let key = "k".repeat(1000);
+        let mut store =
+            DiskStore::open(&dir, TimeUnit::Millis, DiskStoreConfig::default()).unwrap();
+        store.push(&key, sample(1));
+        store.flush().unwrap();
+        drop(store);
+        let store = DiskStore::open(&dir, TimeUnit::Millis, DiskStoreConfig::default()).unwrap();
+        assert_eq!(store.series(&key).unwrap().read(..).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flush_past_failure() {
+        let dir = temp_dir("flush-failure");
+        let mut store =
+            DiskStore::open(&dir, TimeUnit::Millis, DiskStoreConfig::default()).unwrap();
+        // A file in the way of the directory of `a`
+        File::create(dir.join(encode_dir_name("a"))).unwrap();
+        store.push(&"a".into(), sample(1));
+        store.push(&"b".into(), sample(1));
+        let e = store.flush().unwrap_err();
+        assert_eq!(e.failures.len(), 1);
+        assert_eq!(e.failures[0].0, "a");
+        fs::remove_file(dir.join(encode_dir_name("a"))).unwrap();
+        store.flush().unwrap();
+        drop(store);
+        let store = DiskStore::open(&dir, TimeUnit::Millis, DiskStoreConfig::default()).unwrap();
+        for key in ["a", "b"] {
+            assert_eq!(store.series(key).unwrap().read(..).unwrap().len(), 1);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tombstone_after_torn_block() {
+        let dir = temp_dir("torn-tombstone");
+        let open = || DiskStore::open(&dir, TimeUnit::Millis, DiskStoreConfig::default()).unwrap();
+        let key: MetricKey = "a".into();
+        let mut store = open();
+        for time in 1..=3 {
+            store.push(&key, sample(time));
+        }
+        store.flush().unwrap();
+        store.delete(&key, 0..=10).unwrap();
+        store.push(&key, sample(5));
+        store.flush().unwrap();
+        drop(store);
+
+        // A crash tears off the block written after the deletion
+        let path = segment_path(&dir.join(encode_dir_name(&key)), 0);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 1).unwrap();
+        drop(file);
+
+        let mut store = open();
+        assert!(store.series(&key).unwrap().read(..).unwrap().is_empty());
+        store.push(&key, sample(6));
+        store.flush().unwrap();
+        drop(store);
+        let store = open();
+        let times: Vec<Time> = store
+            .series(&key)
+            .unwrap()
+            .read(..)
+            .unwrap()
+            .iter()
+            .map(|sample| sample.time)
+            .collect();
+        assert_eq!(times, [6]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_crash_recovery() {
+        let dir = temp_dir("crash-recovery");
+        let config = DiskStoreConfig {
+            block_samples: 2,
+            ..Default::default()
+        };
+        let open = || DiskStore::open(&dir, TimeUnit::Millis, config).unwrap();
+        let key: MetricKey = "a".into();
+        let mut store = open();
+        for time in 1..=4 {
+            store.push(&key, sample(time));
+        }
+        store.flush().unwrap();
+        drop(store);
+
+        // A torn block, a compaction left halfway and a segment torn while being created
+        let series_dir = dir.join(encode_dir_name(&key));
+        let path = segment_path(&series_dir, 0);
+        let len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0xff; BLOCK_HEADER_SIZE + 3]).unwrap();
+        drop(file);
+        File::create(path.with_extension(TMP_EXTENSION)).unwrap();
+        File::create(segment_path(&series_dir, 1)).unwrap();
+
+        let mut store = open();
+        let read = |store: &DiskStore| -> Vec<Time> {
+            let samples = store.series(&key).unwrap().read(..).unwrap();
+            samples.iter().map(|sample| sample.time).collect()
+        };
+        assert_eq!(read(&store), [1, 2, 3, 4]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert!(!path.with_extension(TMP_EXTENSION).exists());
+        assert!(!segment_path(&series_dir, 1).exists());
+        store.push(&key, sample(5));
+        store.flush().unwrap();
+        drop(store);
+        assert_eq!(read(&open()), [1, 2, 3, 4, 5]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact_runs() {
+        let dir = temp_dir("compact-runs");
+        let config = DiskStoreConfig {
+            block_samples: 4,
+            segment_bytes: 256,
+        };
+        let mut store = DiskStore::open(&dir, TimeUnit::Millis, config).unwrap();
+        let key: MetricKey = "a".into();
+        for time in 0..20 {
+            store.push(&key, sample(time));
+            store.flush().unwrap();
+        }
+        let read = |store: &DiskStore| -> Vec<Time> {
+            let samples = store.series(&key).unwrap().read(..).unwrap();
+            samples.iter().map(|sample| sample.time).collect()
+        };
+        let segments = |store: &DiskStore| store.series(&key).unwrap().segments.len();
+        let before = segments(&store);
+        store.delete(&key, 2..=3).unwrap();
+
+        // Deleted while the compaction runs
+        let mut compaction = store.plan_compaction(|_| Some(1)).unwrap();
+        assert!(!compaction.is_empty());
+        compaction.run().unwrap();
+        store.delete(&key, 4..=4).unwrap();
+        store.finish_compaction(compaction).unwrap();
+        let expected: Vec<Time> = (1..20).filter(|time| !(2..=4).contains(time)).collect();
+        assert_eq!(read(&store), expected);
+        // The sealed segments are each as large as a run may be, so only the first is rewritten
+        assert_eq!(segments(&store), before);
+
+        loop {
+            let mut compaction = store.plan_compaction(|_| Some(1)).unwrap();
+            let done = compaction.is_empty();
+            compaction.run().unwrap();
+            store.finish_compaction(compaction).unwrap();
+            if done {
+                break;
+            }
+        }
+        assert_eq!(read(&store), expected);
+        drop(store);
+        let store = DiskStore::open(&dir, TimeUnit::Millis, config).unwrap();
+        assert_eq!(read(&store), expected);
+        assert!(store.series(&key).unwrap().tombstones.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
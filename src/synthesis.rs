@@ -0,0 +1,787 @@
+use core::ops::{Bound, RangeBounds};
+
+use primitive::ops::range::RangeAny;
+
+use crate::{
+    chunked::ChunkedSpan,
+    consumer::{MetricQueue, MetricQueues, TimeSeries, TimeSeriesSpan},
+    view::{MetricSources, MetricSynthesis, SynthesisError},
+    MetricKey, Sample, Time, TimeUnit,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+impl BinaryOp {
+    pub fn apply(&self, a: f64, b: f64) -> f64 {
+        match self {
+            BinaryOp::Add => a + b,
+            BinaryOp::Sub => a - b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Div => a / b,
+        }
+    }
+}
+
+/// How samples of other series are matched to a timestamp of the first one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Alignment {
+    /// A sample at most this far away counts as at the timestamp
+    pub tolerance: Time,
+    pub interpolation: Interpolation,
+}
+/// Estimates a value when no sample is within the tolerance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Interpolation {
+    /// The timestamp is skipped
+    #[default]
+    None,
+    /// The latest earlier sample
+    Previous,
+    /// The line between the surrounding samples
+    Linear,
+}
+impl Alignment {
+    /// `samples` must be sorted by time
+    pub fn value_at(&self, samples: &[Sample], time: Time) -> Option<f64> {
+        self.value_between(neighbors(samples, time), time)
+    }
+    /// Where the samples matched to the timestamps within a range are
+    pub(crate) fn reach(&self) -> Reach {
+        Reach {
+            before: self.tolerance,
+            after: self.tolerance,
+            neighbors: self.interpolation != Interpolation::None,
+        }
+    }
+    /// Given the latest sample before `time` and the earliest one at or after it
+    fn value_between(
+        &self,
+        (prev, next): (Option<Sample>, Option<Sample>),
+        time: Time,
+    ) -> Option<f64> {
+        let nearest = [prev, next]
+            .into_iter()
+            .flatten()
+            .filter(|sample| sample.time.abs_diff(time) <= self.tolerance)
+            .min_by_key(|sample| sample.time.abs_diff(time));
+        if let Some(sample) = nearest {
+            return Some(sample.value);
+        }
+        match self.interpolation {
+            Interpolation::None => None,
+            Interpolation::Previous => prev.map(|sample| sample.value),
+            Interpolation::Linear => {
+                let (prev, next) = (prev?, next?);
+                let progress = (time - prev.time) as f64 / (next.time - prev.time) as f64;
+                Some(prev.value + (next.value - prev.value) * progress)
+            }
+        }
+    }
+}
+
+/// Folds the series of `keys` from left to right with `op` at each timestamp of the first series
+#[derive(Debug, Clone)]
+pub struct Combine {
+    op: BinaryOp,
+    keys: Vec<MetricKey>,
+    alignment: Alignment,
+}
+impl Combine {
+    pub fn new(op: BinaryOp, keys: Vec<MetricKey>, alignment: Alignment) -> Self {
+        Self {
+            op,
+            keys,
+            alignment,
+        }
+    }
+    pub fn sum(keys: Vec<MetricKey>) -> Self {
+        Self::new(BinaryOp::Add, keys, Alignment::default())
+    }
+    pub fn difference(keys: Vec<MetricKey>) -> Self {
+        Self::new(BinaryOp::Sub, keys, Alignment::default())
+    }
+    pub fn product(keys: Vec<MetricKey>) -> Self {
+        Self::new(BinaryOp::Mul, keys, Alignment::default())
+    }
+    pub fn ratio(keys: Vec<MetricKey>) -> Self {
+        Self::new(BinaryOp::Div, keys, Alignment::default())
+    }
+    pub fn with_alignment(self, alignment: Alignment) -> Self {
+        Self { alignment, ..self }
+    }
+}
+impl MetricSynthesis for Combine {
+    fn span<'a>(
+        &'a self,
+        sources: &MetricSources<'a>,
+        time_range: RangeAny<Time>,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        let (first, rest) = self
+            .keys
+            .split_first()
+            .ok_or(SynthesisError::SeriesCount(0))?;
+        let first = SortedSamples::from_sources(sources, first, time_range)?.into_span();
+        // Samples outside the range might still be the closest ones
+        let reach = self.alignment.reach();
+        let rest = rest
+            .iter()
+            .map(|key| SortedSamples::read(sources, key, time_range, reach))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Timestamps that fail to align are skipped, so the count is only known after them
+        let samples = first.samples.filter_map(|sample| {
+            let mut value = sample.value;
+            for samples in &rest {
+                let other = samples.value_at(&self.alignment, sample.time)?;
+                value = self.op.apply(value, other);
+            }
+            Some(Sample {
+                time: sample.time,
+                value,
+            })
+        });
+        Ok(owned_span(samples.collect()))
+    }
+}
+
+/// Applies `op` between each sample of `key` and `scalar`
+#[derive(Debug, Clone)]
+pub struct Scalar {
+    key: MetricKey,
+    op: BinaryOp,
+    scalar: f64,
+    /// The scalar is the left operand
+    scalar_first: bool,
+}
+impl Scalar {
+    /// `sample op scalar`
+    pub fn new(key: MetricKey, op: BinaryOp, scalar: f64) -> Self {
+        Self {
+            key,
+            op,
+            scalar,
+            scalar_first: false,
+        }
+    }
+    /// `scalar op sample`
+    pub fn scalar_first(key: MetricKey, op: BinaryOp, scalar: f64) -> Self {
+        Self {
+            key,
+            op,
+            scalar,
+            scalar_first: true,
+        }
+    }
+}
+impl MetricSynthesis for Scalar {
+    fn span<'a>(
+        &'a self,
+        sources: &MetricSources<'a>,
+        time_range: RangeAny<Time>,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        let span = sources.span(&self.key, time_range)?;
+        let samples = span.samples.map(move |sample| {
+            let value = match self.scalar_first {
+                true => self.op.apply(self.scalar, sample.value),
+                false => self.op.apply(sample.value, self.scalar),
+            };
+            Sample {
+                time: sample.time,
+                value,
+            }
+        });
+        Ok(TimeSeriesSpan {
+            samples: Box::new(samples),
+            count: span.count,
+        })
+    }
+}
+
+/// Per-second change of a series
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateKind {
+    /// Average increase of a counter over the trailing `window`
+    Rate { window: Time },
+    /// Increase of a counter since the previous sample
+    Irate,
+    /// Change of a gauge since the previous sample
+    Derivative,
+}
+
+/// [`RateKind`] of the series of `key`
+///
+/// A counter dropping is taken as a reset to zero rather than a negative increase.
+#[derive(Debug, Clone)]
+pub struct Rate {
+    key: MetricKey,
+    kind: RateKind,
+    time_unit: TimeUnit,
+    /// Samples further apart than this have no change between them
+    max_gap: Option<Time>,
+}
+impl Rate {
+    /// `time_unit` is the one of the queue of `key`
+    pub fn new(key: MetricKey, kind: RateKind, time_unit: TimeUnit) -> Self {
+        Self {
+            key,
+            kind,
+            time_unit,
+            max_gap: None,
+        }
+    }
+    pub fn windowed(key: MetricKey, window: Time, time_unit: TimeUnit) -> Self {
+        Self::new(key, RateKind::Rate { window }, time_unit)
+    }
+    pub fn irate(key: MetricKey, time_unit: TimeUnit) -> Self {
+        Self::new(key, RateKind::Irate, time_unit)
+    }
+    pub fn derivative(key: MetricKey, time_unit: TimeUnit) -> Self {
+        Self::new(key, RateKind::Derivative, time_unit)
+    }
+    pub fn with_max_gap(self, max_gap: Time) -> Self {
+        Self {
+            max_gap: Some(max_gap),
+            ..self
+        }
+    }
+}
+impl MetricSynthesis for Rate {
+    fn span<'a>(
+        &'a self,
+        sources: &MetricSources<'a>,
+        time_range: RangeAny<Time>,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        // Samples before the range are needed for the first rates within it
+        let reach = match self.kind {
+            RateKind::Rate { window } => Reach::before(window),
+            RateKind::Irate | RateKind::Derivative => Reach::neighbors(),
+        };
+        let samples = SortedSamples::read(sources, &self.key, time_range, reach)?;
+        let mut samples = rates(samples.iter(), self.kind, self.time_unit, self.max_gap);
+        samples.retain(|sample| time_range.contains(&sample.time));
+        Ok(owned_span(samples))
+    }
+}
+
+/// `samples` must be sorted by time
+///
+/// The first sample and those right after a gap have no rate.
+pub fn rates<'a>(
+    samples: impl IntoIterator<Item = &'a Sample, IntoIter: Clone>,
+    kind: RateKind,
+    time_unit: TimeUnit,
+    max_gap: Option<Time>,
+) -> Vec<Sample> {
+    let samples = samples.into_iter();
+    let ticks_per_second = time_unit.ticks_per_second() as f64;
+    let is_gap = |prev: &Sample, next: &Sample| {
+        max_gap.is_some_and(|max_gap| max_gap < next.time - prev.time)
+    };
+    let counter_increase = |prev: &Sample, next: &Sample| match next.value < prev.value {
+        true => next.value,
+        false => next.value - prev.value,
+    };
+    let per_second = |value: f64, from: Time, to: Time| Sample {
+        time: to,
+        value: value * ticks_per_second / (to - from) as f64,
+    };
+    match kind {
+        RateKind::Irate | RateKind::Derivative => samples
+            .clone()
+            .zip(samples.skip(1))
+            .filter(|(prev, next)| prev.time != next.time && !is_gap(prev, next))
+            .map(|(prev, next)| {
+                let change = match kind {
+                    RateKind::Derivative => next.value - prev.value,
+                    _ => counter_increase(prev, next),
+                };
+                per_second(change, prev.time, next.time)
+            })
+            .collect(),
+        RateKind::Rate { window } => {
+            // The start of the window trails behind on its own pass over the samples
+            let mut trailing = samples.clone();
+            let Some(first) = trailing.next() else {
+                return vec![];
+            };
+            // Index, sample and total increase up to it of where the window and the run without gaps start
+            let mut start = (0, first, 0.);
+            let mut run_start = start;
+            let (mut prev, mut total) = (first, 0.);
+            let mut rates = vec![];
+            for (i, sample) in samples.enumerate().skip(1) {
+                match is_gap(prev, sample) {
+                    true => run_start = (i, sample, total),
+                    false => total += counter_increase(prev, sample),
+                }
+                prev = sample;
+                while start.1.time < sample.time.saturating_sub(window) {
+                    let Some(next) = trailing.next() else {
+                        break;
+                    };
+                    let increase = match is_gap(start.1, next) {
+                        true => 0.,
+                        false => counter_increase(start.1, next),
+                    };
+                    start = (start.0 + 1, next, start.2 + increase);
+                }
+                let (_, first, increases) = match start.0 < run_start.0 {
+                    true => run_start,
+                    false => start,
+                };
+                if first.time == sample.time {
+                    continue;
+                }
+                rates.push(per_second(total - increases, first.time, sample.time));
+            }
+            rates
+        }
+    }
+}
+
+/// How far out of a time range an input is read for the outputs within it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Reach {
+    /// Time before the start of the range
+    pub before: Time,
+    /// Time after the end of the range
+    pub after: Time,
+    /// Whether the latest sample before those times and the earliest one after them are read too
+    pub neighbors: bool,
+}
+impl Reach {
+    pub fn before(before: Time) -> Self {
+        Self {
+            before,
+            ..Default::default()
+        }
+    }
+    pub fn after(after: Time) -> Self {
+        Self {
+            after,
+            ..Default::default()
+        }
+    }
+    pub fn neighbors() -> Self {
+        Self {
+            neighbors: true,
+            ..Default::default()
+        }
+    }
+    /// Reach into an input of something that is itself read with `outer`
+    pub fn and(self, outer: Self) -> Self {
+        Self {
+            before: self.before.saturating_add(outer.before),
+            after: self.after.saturating_add(outer.after),
+            neighbors: self.neighbors || outer.neighbors,
+        }
+    }
+    /// `time_range` widened by the times before and after it
+    pub fn widen(&self, time_range: &impl RangeBounds<Time>) -> RangeAny<Time> {
+        let start = match time_range.start_bound() {
+            Bound::Included(&start) => Bound::Included(start.saturating_sub(self.before)),
+            Bound::Excluded(&start) => start
+                .checked_sub(self.before)
+                .map_or(Bound::Unbounded, Bound::Excluded),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match time_range.end_bound() {
+            Bound::Included(&end) => Bound::Included(end.saturating_add(self.after)),
+            Bound::Excluded(&end) => end
+                .checked_add(self.after)
+                .map_or(Bound::Unbounded, Bound::Excluded),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        RangeAny::from_range((start, end))
+    }
+}
+
+/// Samples of an input in order of time, borrowed if it is a raw queue in order
+pub(crate) enum SortedSamples<'a> {
+    Borrowed(ChunkedSpan<'a, Sample>),
+    Owned(Vec<Sample>),
+}
+impl<'a> SortedSamples<'a> {
+    pub fn new(queue: &'a MetricQueue, time_range: impl core::ops::RangeBounds<Time>) -> Self {
+        let span = queue.span(time_range);
+        if queue.is_sorted() {
+            return Self::Borrowed(span);
+        }
+        let mut samples = span.iter().copied().collect::<Vec<_>>();
+        // Queues under `LatePolicy::Append` might be out of order
+        samples.sort_by_key(|sample| sample.time);
+        Self::Owned(samples)
+    }
+    pub fn from_sources(
+        sources: &MetricSources<'a>,
+        key: &str,
+        time_range: impl core::ops::RangeBounds<Time> + Clone,
+    ) -> Result<Self, SynthesisError> {
+        Self::read(sources, key, time_range, Reach::default())
+    }
+    /// Samples of `key` within `time_range` widened by `reach`
+    pub fn read(
+        sources: &MetricSources<'a>,
+        key: &str,
+        time_range: impl core::ops::RangeBounds<Time>,
+        reach: Reach,
+    ) -> Result<Self, SynthesisError> {
+        let range = reach.widen(&time_range);
+        if let Some(queue) = sources.metrics().get(key) {
+            if !reach.neighbors {
+                return Ok(Self::new(queue, range));
+            }
+            if queue.is_sorted() {
+                let all = queue.span(..);
+                let start = all.partition_point(|sample| is_before(&range, sample.time));
+                let end = all.partition_point(|sample| !is_after(&range, sample.time));
+                return Ok(Self::Borrowed(
+                    all.sub_span(start.saturating_sub(1)..end + 1),
+                ));
+            }
+            let span = |range| -> Result<Samples<'a>, SynthesisError> {
+                Ok(Box::new(queue.span(range).iter().copied()))
+            };
+            return Ok(Self::Owned(read_sorted(span, range, true)?));
+        }
+        let span = |range| -> Result<Samples<'a>, SynthesisError> {
+            Ok(sources.span(key, range)?.samples)
+        };
+        Ok(Self::Owned(read_sorted(span, range, reach.neighbors)?))
+    }
+
+    pub fn value_at(&self, alignment: &Alignment, time: Time) -> Option<f64> {
+        let neighbors = match self {
+            SortedSamples::Borrowed(span) => {
+                let pos = span.partition_point(|sample| sample.time < time);
+                let prev = pos.checked_sub(1).and_then(|i| span.get(i));
+                (prev.copied(), span.get(pos).copied())
+            }
+            SortedSamples::Owned(samples) => neighbors(samples, time),
+        };
+        alignment.value_between(neighbors, time)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Sample> + Clone + '_ {
+        let (borrowed, owned) = match self {
+            SortedSamples::Borrowed(span) => (Some(span.iter()), None),
+            SortedSamples::Owned(samples) => (None, Some(samples.iter())),
+        };
+        borrowed
+            .into_iter()
+            .flatten()
+            .chain(owned.into_iter().flatten())
+    }
+
+    pub fn into_span(self) -> TimeSeriesSpan<'a> {
+        match self {
+            SortedSamples::Borrowed(span) => TimeSeriesSpan {
+                samples: Box::new(span.iter().copied()),
+                count: span.len(),
+            },
+            SortedSamples::Owned(samples) => owned_span(samples),
+        }
+    }
+    pub fn into_vec(self) -> Vec<Sample> {
+        match self {
+            SortedSamples::Borrowed(span) => span.iter().copied().collect(),
+            SortedSamples::Owned(samples) => samples,
+        }
+    }
+}
+type Samples<'a> = Box<dyn Iterator<Item = Sample> + Send + 'a>;
+/// Samples that `span` reads within `range` in order of time
+///
+/// With `neighbors`, also the latest sample before the range and the earliest one after it.
+pub(crate) fn read_sorted<'a>(
+    span: impl Fn(RangeAny<Time>) -> Result<Samples<'a>, SynthesisError>,
+    range: RangeAny<Time>,
+    neighbors: bool,
+) -> Result<Vec<Sample>, SynthesisError> {
+    let mut samples = span(range)?.collect::<Vec<_>>();
+    // Out-of-order queues are only trimmed at their ends
+    samples.retain(|sample| range.contains(&sample.time));
+    if neighbors {
+        if let Some(before) = match range.start_bound() {
+            Bound::Included(&start) => Some(RangeAny::from_range(..start)),
+            Bound::Excluded(&start) => Some(RangeAny::from_range(..=start)),
+            Bound::Unbounded => None,
+        } {
+            let prev = span(before)?.filter(|sample| before.contains(&sample.time));
+            samples.extend(prev.max_by_key(|sample| sample.time));
+        }
+        if let Some(after) = match range.end_bound() {
+            Bound::Included(&end) => Some(RangeAny::from_range((
+                Bound::Excluded(end),
+                Bound::Unbounded,
+            ))),
+            Bound::Excluded(&end) => Some(RangeAny::from_range(end..)),
+            Bound::Unbounded => None,
+        } {
+            let next = span(after)?.filter(|sample| after.contains(&sample.time));
+            samples.extend(next.min_by_key(|sample| sample.time));
+        }
+    }
+    samples.sort_by_key(|sample| sample.time);
+    Ok(samples)
+}
+fn is_before(range: &impl RangeBounds<Time>, time: Time) -> bool {
+    match range.start_bound() {
+        Bound::Included(&start) => time < start,
+        Bound::Excluded(&start) => time <= start,
+        Bound::Unbounded => false,
+    }
+}
+fn is_after(range: &impl RangeBounds<Time>, time: Time) -> bool {
+    match range.end_bound() {
+        Bound::Included(&end) => end < time,
+        Bound::Excluded(&end) => end <= time,
+        Bound::Unbounded => false,
+    }
+}
+
+/// The latest sample before `time` and the earliest one at or after it
+fn neighbors(samples: &[Sample], time: Time) -> (Option<Sample>, Option<Sample>) {
+    let pos = samples.partition_point(|sample| sample.time < time);
+    let prev = pos.checked_sub(1).map(|i| samples[i]);
+    (prev, samples.get(pos).copied())
+}
+
+pub(crate) fn sorted_samples(
+    metrics: &MetricQueues,
+    key: &str,
+    time_range: RangeAny<Time>,
+) -> Option<Vec<Sample>> {
+    let span = TimeSeries::span(&**metrics.get(key)?, time_range)?;
+    let mut samples = span.samples.collect::<Vec<_>>();
+    // Queues under `LatePolicy::Append` might be out of order
+    samples.sort_by_key(|sample| sample.time);
+    Some(samples)
+}
+pub(crate) fn owned_span(samples: Vec<Sample>) -> TimeSeriesSpan<'static> {
+    let count = samples.len();
+    TimeSeriesSpan {
+        samples: Box::new(samples.into_iter()),
+        count,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::view::MetricSyntheses;
+
+    use super::*;
+
+    fn samples(samples: &[(Time, f64)]) -> Vec<Sample> {
+        samples
+            .iter()
+            .map(|&(time, value)| Sample { time, value })
+            .collect()
+    }
+    fn queues(series: &[(&str, &[(Time, f64)])]) -> MetricQueues {
+        let mut metrics = MetricQueues::new();
+        for (key, series) in series {
+            let mut queue = MetricQueue::new();
+            for &sample in &samples(series) {
+                queue.push(sample, 16).unwrap();
+            }
+            metrics.insert(key.to_string(), Arc::new(queue));
+        }
+        metrics
+    }
+
+    #[test]
+    fn test_alignment() {
+        let samples = samples(&[(0, 100.), (20, 200.)]);
+        let alignment = |tolerance, interpolation| Alignment {
+            tolerance,
+            interpolation,
+        };
+        let none = alignment(3, Interpolation::None);
+        assert_eq!(none.value_at(&samples, 3), Some(100.));
+        assert_eq!(none.value_at(&samples, 17), Some(200.));
+        assert_eq!(none.value_at(&samples, 10), None);
+        let previous = alignment(0, Interpolation::Previous);
+        assert_eq!(previous.value_at(&samples, 10), Some(100.));
+        assert_eq!(previous.value_at(&samples, 30), Some(200.));
+        let linear = alignment(0, Interpolation::Linear);
+        assert_eq!(linear.value_at(&samples, 5), Some(125.));
+        assert_eq!(linear.value_at(&samples, 30), None);
+        // The nearest sample within the tolerance wins over interpolation
+        let linear = alignment(6, Interpolation::Linear);
+        assert_eq!(linear.value_at(&samples, 5), Some(100.));
+    }
+
+    #[test]
+    fn test_combine() {
+        let metrics = queues(&[
+            ("a", &[(0, 1.), (10, 2.), (20, 3.), (30, 4.)]),
+            ("b", &[(0, 100.), (20, 200.)]),
+        ]);
+        let syntheses = MetricSyntheses::new();
+        let sources = MetricSources::new(&metrics, &syntheses);
+        let keys = vec!["a".to_string(), "b".to_string()];
+        let combine = |interpolation| {
+            let alignment = Alignment {
+                tolerance: 0,
+                interpolation,
+            };
+            let combine = Combine::sum(keys.clone()).with_alignment(alignment);
+            let span = combine.span(&sources, RangeAny::from_range(..)).unwrap();
+            let count = span.count;
+            let samples: Vec<(Time, f64)> = span
+                .samples
+                .map(|sample| (sample.time, sample.value))
+                .collect();
+            assert_eq!(count, samples.len());
+            samples
+        };
+        assert_eq!(combine(Interpolation::None), [(0, 101.), (20, 203.)]);
+        assert_eq!(
+            combine(Interpolation::Previous),
+            [(0, 101.), (10, 102.), (20, 203.), (30, 204.)]
+        );
+        assert_eq!(
+            combine(Interpolation::Linear),
+            [(0, 101.), (10, 152.), (20, 203.)]
+        );
+        // The other series are matched outside of the range
+        let combine = Combine::ratio(vec!["b".to_string(), "a".to_string()]);
+        let span = combine.span(&sources, RangeAny::from_range(20..)).unwrap();
+        let samples: Vec<(Time, f64)> = span
+            .samples
+            .map(|sample| (sample.time, sample.value))
+            .collect();
+        assert_eq!(samples, [(20, 200. / 3.)]);
+        let missing = Combine::sum(vec!["a".to_string(), "c".to_string()]);
+        assert!(missing.span(&sources, RangeAny::from_range(..)).is_err());
+    }
+
+    #[test]
+    fn test_read() {
+        let metrics = queues(&[("a", &[(0, 1.), (10, 2.), (20, 3.), (30, 4.), (40, 5.)])]);
+        let mut syntheses = MetricSyntheses::new();
+        let scaled = Scalar::new("a".to_string(), BinaryOp::Mul, 1.);
+        syntheses.insert(
+            "s".to_string(),
+            Box::new(scaled) as Box<dyn MetricSynthesis>,
+        );
+        let sources = MetricSources::new(&metrics, &syntheses);
+        let read = |key, time_range: RangeAny<Time>, reach| {
+            let samples = SortedSamples::read(&sources, key, time_range, reach).unwrap();
+            let borrowed = matches!(samples, SortedSamples::Borrowed(_));
+            let times: Vec<Time> = samples.iter().map(|sample| sample.time).collect();
+            (borrowed, times)
+        };
+        let reach = Reach::before(5).and(Reach::neighbors());
+        assert_eq!(
+            read("a", RangeAny::from_range(20..=30), reach),
+            (true, vec![10, 20, 30, 40])
+        );
+        assert_eq!(
+            read("s", RangeAny::from_range(20..=30), reach),
+            (false, vec![10, 20, 30, 40])
+        );
+        assert_eq!(
+            read("a", RangeAny::from_range(25..), Reach::before(5)),
+            (true, vec![20, 30, 40])
+        );
+        let range = RangeAny::from_range((Bound::Excluded(3), Bound::Excluded(30)));
+        let times = vec![0, 10, 20, 30];
+        assert_eq!(
+            read("a", range, Reach::before(5).and(Reach::after(1))),
+            (true, times)
+        );
+    }
+
+    #[test]
+    fn test_rate_spans() {
+        let series: &[(Time, f64)] = &[
+            (0, 0.),
+            (10, 10.),
+            (20, 20.),
+            (30, 5.),
+            (40, 15.),
+            (100, 25.),
+            (110, 30.),
+        ];
+        let metrics = queues(&[("a", series)]);
+        let mut syntheses = MetricSyntheses::new();
+        let scaled = Scalar::new("a".to_string(), BinaryOp::Mul, 1.);
+        syntheses.insert(
+            "s".to_string(),
+            Box::new(scaled) as Box<dyn MetricSynthesis>,
+        );
+        let sources = MetricSources::new(&metrics, &syntheses);
+        let samples = samples(series);
+        for kind in [
+            RateKind::Irate,
+            RateKind::Derivative,
+            RateKind::Rate { window: 25 },
+        ] {
+            let all = rates(&samples, kind, TimeUnit::Seconds, Some(30));
+            for key in ["a", "s"] {
+                let rate = Rate::new(key.to_string(), kind, TimeUnit::Seconds).with_max_gap(30);
+                // Only the samples reaching into the range are read for it
+                for time_range in [
+                    RangeAny::from_range(30..=40),
+                    RangeAny::from_range(45..),
+                    RangeAny::from_range(..20),
+                ] {
+                    let span = rate.span(&sources, time_range).unwrap();
+                    let expected = all
+                        .iter()
+                        .filter(|sample| time_range.contains(&sample.time));
+                    let expected: Vec<(Time, f64)> =
+                        expected.map(|sample| (sample.time, sample.value)).collect();
+                    let rates: Vec<(Time, f64)> = span
+                        .samples
+                        .map(|sample| (sample.time, sample.value))
+                        .collect();
+                    assert_eq!(rates, expected, "{kind:?} of {key} over {time_range:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rates() {
+        // A counter reset at 30 and a gap before 100
+        let samples: Vec<Sample> = [
+            (0, 0.),
+            (10, 10.),
+            (20, 20.),
+            (30, 5.),
+            (40, 15.),
+            (100, 25.),
+        ]
+        .into_iter()
+        .map(|(time, value)| Sample { time, value })
+        .collect();
+        let rates = |kind| -> Vec<(Time, f64)> {
+            let rates = rates(&samples, kind, TimeUnit::Seconds, Some(30));
+            rates
+                .iter()
+                .map(|sample| (sample.time, sample.value))
+                .collect()
+        };
+        assert_eq!(
+            rates(RateKind::Irate),
+            [(10, 1.), (20, 1.), (30, 0.5), (40, 1.)]
+        );
+        assert_eq!(
+            rates(RateKind::Derivative),
+            [(10, 1.), (20, 1.), (30, -1.5), (40, 1.)]
+        );
+        assert_eq!(
+            rates(RateKind::Rate { window: 20 }),
+            [(10, 1.), (20, 1.), (30, 0.75), (40, 0.75)]
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, mem::MaybeUninit};
+use std::{collections::HashMap, mem::MaybeUninit, num::NonZeroU64, ops::Bound};
 
 use plotly::{
     layout::{Axis, AxisType},
@@ -7,58 +7,280 @@ use plotly::{
 use primitive::{iter::chunk::Chunks, ops::range::RangeAny};
 
 use crate::{
-    consumer::{MetricQueues, TimeSeries, TimeSeriesSpan},
-    MetricKey, Time,
+    consumer::{matching_keys, KeyPattern, MetricQueues, TimeSeries, TimeSeriesSpan},
+    expr::Expression,
+    resample::{Fill, Reducer, Resampler},
+    MetricKey, Time, TimeUnit,
 };
 
 const MAX_DISPLAY_DATA_POINTS: usize = 1024;
 
 pub trait MetricSynthesis: core::fmt::Debug + Sync + Send {
-    fn span(
-        &self,
-        metrics: &MetricQueues,
+    /// The span might borrow both the synthesis and the source queues
+    fn span<'a>(
+        &'a self,
+        sources: &MetricSources<'a>,
         time_range: RangeAny<Time>,
-    ) -> Option<TimeSeriesSpan<'_>>;
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError>;
 }
 pub type MetricSyntheses = HashMap<MetricKey, Box<dyn MetricSynthesis>>;
 
+/// What a [`MetricSynthesis`] reads its inputs from: the raw queues and the other syntheses
+#[derive(Debug, Clone)]
+pub struct MetricSources<'a> {
+    metrics: &'a MetricQueues,
+    syntheses: &'a MetricSyntheses,
+    /// Keys of the syntheses being evaluated, outermost first
+    path: Vec<&'a str>,
+}
+impl<'a> MetricSources<'a> {
+    pub fn new(metrics: &'a MetricQueues, syntheses: &'a MetricSyntheses) -> Self {
+        Self {
+            metrics,
+            syntheses,
+            path: vec![],
+        }
+    }
+
+    pub fn metrics(&self) -> &'a MetricQueues {
+        self.metrics
+    }
+    pub fn syntheses(&self) -> &'a MetricSyntheses {
+        self.syntheses
+    }
+    /// Whether `key` is a synthesis that is reading its inputs
+    pub fn is_evaluating(&self, key: &str) -> bool {
+        self.path.contains(&key)
+    }
+
+    pub fn span(
+        &self,
+        key: &str,
+        time_range: impl core::ops::RangeBounds<Time> + Clone,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        self.span_at(key, time_range, 0)
+    }
+    /// Raw queues might answer from their rollups; see [`TimeSeries::span_at`]
+    pub fn span_at(
+        &self,
+        key: &str,
+        time_range: impl core::ops::RangeBounds<Time> + Clone,
+        resolution: Time,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        let missing = || SynthesisError::Missing(key.to_owned());
+        if let Some(queue) = self.metrics.get(key) {
+            return TimeSeries::span_at(&**queue, time_range, resolution).ok_or_else(missing);
+        }
+        let (key, synthesis) = self.syntheses.get_key_value(key).ok_or_else(missing)?;
+        if let Some(start) = self.path.iter().position(|parent| *parent == key) {
+            let mut cycle: Vec<MetricKey> = self.path[start..]
+                .iter()
+                .map(|&key| key.to_owned())
+                .collect();
+            cycle.push(key.clone());
+            return Err(SynthesisError::Cycle(cycle));
+        }
+        let mut path = self.path.clone();
+        path.push(key);
+        let sources = Self {
+            metrics: self.metrics,
+            syntheses: self.syntheses,
+            path,
+        };
+        synthesis.span(&sources, RangeAny::from_range(time_range))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SynthesisError {
+    /// No queue or synthesis has the key
+    Missing(MetricKey),
+    /// Syntheses that read each other, from the first one back to itself
+    Cycle(Vec<MetricKey>),
+    /// Inputs that do not make exactly one series
+    SeriesCount(usize),
+}
+impl core::fmt::Display for SynthesisError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SynthesisError::Missing(key) => write!(f, "missing series `{key}`"),
+            SynthesisError::Cycle(keys) => write!(f, "cyclic syntheses `{}`", keys.join("` -> `")),
+            SynthesisError::SeriesCount(n) => write!(f, "{n} series instead of one"),
+        }
+    }
+}
+impl std::error::Error for SynthesisError {}
+
 pub fn metric_span<'a>(
     metrics: &'a MetricQueues,
     syntheses: &'a MetricSyntheses,
     key: &str,
     time_range: impl core::ops::RangeBounds<Time> + Clone,
 ) -> Option<TimeSeriesSpan<'a>> {
-    let queue = metrics.get(key);
-    let synthesis = syntheses.get(key);
-    match (queue, synthesis) {
-        (Some(queue), _) => TimeSeries::span(queue, time_range.clone()),
-        (None, Some(synthesis)) => {
-            synthesis.span(metrics, RangeAny::from_range(time_range.clone()))
-        }
-        (None, None) => None,
+    metric_span_at(metrics, syntheses, key, time_range, 0)
+}
+/// Raw queues might answer from their rollups; see [`TimeSeries::span_at`]
+pub fn metric_span_at<'a>(
+    metrics: &'a MetricQueues,
+    syntheses: &'a MetricSyntheses,
+    key: &str,
+    time_range: impl core::ops::RangeBounds<Time> + Clone,
+    resolution: Time,
+) -> Option<TimeSeriesSpan<'a>> {
+    try_metric_span_at(metrics, syntheses, key, time_range, resolution).ok()
+}
+/// [`metric_span`] telling why a series is unavailable
+pub fn try_metric_span<'a>(
+    metrics: &'a MetricQueues,
+    syntheses: &'a MetricSyntheses,
+    key: &str,
+    time_range: impl core::ops::RangeBounds<Time> + Clone,
+) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+    try_metric_span_at(metrics, syntheses, key, time_range, 0)
+}
+pub fn try_metric_span_at<'a>(
+    metrics: &'a MetricQueues,
+    syntheses: &'a MetricSyntheses,
+    key: &str,
+    time_range: impl core::ops::RangeBounds<Time> + Clone,
+    resolution: Time,
+) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+    MetricSources::new(metrics, syntheses).span_at(key, time_range, resolution)
+}
+
+/// Keys of the queues and syntheses matching `pattern` in order
+///
+/// Only the queues under the [`KeyPattern::literal_prefix`] are scanned; all the syntheses are.
+pub fn select_keys<'a>(
+    metrics: &'a MetricQueues,
+    syntheses: &'a MetricSyntheses,
+    pattern: &KeyPattern,
+) -> Vec<&'a MetricKey> {
+    if let KeyPattern::Exact(key) = pattern {
+        let queue = metrics.get_key_value(key).map(|(key, _)| key);
+        let synthesis = syntheses.get_key_value(key).map(|(key, _)| key);
+        return queue.or(synthesis).into_iter().collect();
     }
+    let mut keys: Vec<&MetricKey> = matching_keys(metrics, pattern).collect();
+    let mut synthesized: Vec<&MetricKey> = syntheses
+        .keys()
+        .filter(|key| pattern.matches(key) && !metrics.contains_key(*key))
+        .collect();
+    if synthesized.is_empty() {
+        return keys;
+    }
+    keys.append(&mut synthesized);
+    keys.sort_unstable();
+    keys
+}
+/// Spans of all the series matching `pattern` in order of their keys
+pub fn metric_spans<'a>(
+    metrics: &'a MetricQueues,
+    syntheses: &'a MetricSyntheses,
+    pattern: &KeyPattern,
+    time_range: impl core::ops::RangeBounds<Time> + Clone,
+) -> Vec<(&'a MetricKey, TimeSeriesSpan<'a>)> {
+    select_keys(metrics, syntheses, pattern)
+        .into_iter()
+        .filter_map(|key| {
+            let span = metric_span(metrics, syntheses, key, time_range.clone())?;
+            Some((key, span))
+        })
+        .collect()
 }
 
+/// Each of `keys` is either an [`Expression`] or parsed by [`KeyPattern::parse`] and expanded to the matching series
+///
+/// A key of an existing series is never taken as an expression.
+/// Keys that fail to parse or to evaluate are listed above the chart with why.
+/// Over a bounded `time_range`, the series are resampled onto a common grid by their latest sample in each step,
+/// and the aggregations in expressions bucket samples at the same step.
 pub async fn scatter_chart_html(
     metrics: &MetricQueues,
     syntheses: &MetricSyntheses,
     keys: impl Iterator<Item = impl AsRef<str>>,
     time_range: impl core::ops::RangeBounds<Time> + Clone,
+    time_unit: TimeUnit,
     value_range: Option<(f64, f64)>,
     div_id: Option<&str>,
 ) -> String {
+    let resolution = match (time_range.start_bound(), time_range.end_bound()) {
+        (
+            Bound::Included(&start) | Bound::Excluded(&start),
+            Bound::Included(&end) | Bound::Excluded(&end),
+        ) => end.saturating_sub(start) / MAX_DISPLAY_DATA_POINTS as Time,
+        _ => 0,
+    };
+    let resampler =
+        NonZeroU64::new(resolution).map(|step| Resampler::new(step, Reducer::Last, Fill::Null));
+    let keys: Vec<String> = keys.map(|key| key.as_ref().to_owned()).collect();
+    let mut expressions = vec![];
+    let mut errors = vec![];
+    let mut selected: Vec<(&str, Option<usize>)> = vec![];
+    for key in &keys {
+        let known = metrics.contains_key(key) || syntheses.contains_key(key);
+        match Expression::parse(key, time_unit) {
+            Ok(expression) if !known && !expression.is_key() => {
+                selected.push((key, Some(expressions.len())));
+                expressions.push(expression.with_step(resolution));
+            }
+            _ => {
+                let pattern = match KeyPattern::parse(key) {
+                    Ok(pattern) => pattern,
+                    Err(e) => {
+                        errors.push(format!("`{key}`: {e}"));
+                        continue;
+                    }
+                };
+                for key in select_keys(metrics, syntheses, &pattern) {
+                    if !selected.contains(&(key, None)) {
+                        selected.push((key, None));
+                    }
+                }
+            }
+        }
+    }
     let mut data_point_count = 0;
     let mut data_sets = vec![];
-    for key in keys {
-        let span = metric_span(metrics, syntheses, key.as_ref(), time_range.clone());
-        let Some(span) = span else {
-            continue;
+    for (key, expression) in selected {
+        let spans: Vec<(String, TimeSeriesSpan)> = match expression {
+            Some(i) => {
+                let series = expressions[i].series(metrics, syntheses, time_range.clone());
+                let series = series.unwrap_or_else(|e| {
+                    errors.push(format!("`{key}`: {e}"));
+                    vec![]
+                });
+                // A single series is named by the expression itself
+                let single = series.len() == 1;
+                let series = series.into_iter().map(|(name, span)| match single {
+                    true => (key.to_owned(), span),
+                    false => (name, span),
+                });
+                series.collect()
+            }
+            None => {
+                let span =
+                    try_metric_span_at(metrics, syntheses, key, time_range.clone(), resolution);
+                match span {
+                    Ok(span) => vec![(key.to_owned(), span)],
+                    Err(e) => {
+                        errors.push(format!("`{key}`: {e}"));
+                        vec![]
+                    }
+                }
+            }
         };
-        if span.count == 0 {
-            continue;
+        for (name, span) in spans {
+            let span = match resampler {
+                Some(resampler) => resampler.resample_span(span, time_range.clone()),
+                None => span,
+            };
+            if span.count == 0 {
+                continue;
+            }
+            data_point_count += span.count;
+            data_sets.push((name, span));
         }
-        data_point_count += span.count;
-        data_sets.push((key, span));
         tokio::task::yield_now().await;
     }
     let mut traces = vec![];
@@ -68,7 +290,8 @@ pub async fn scatter_chart_html(
         let mut reduced_x = vec![];
         let mut reduced_y = vec![];
         span.samples.chunks(&mut tmp_tray, |tray| {
-            reduced_x.push(tray.last().unwrap().time);
+            // Plotly takes dates as milliseconds since the Unix epoch
+            reduced_x.push(time_unit.as_millis_f64(tray.last().unwrap().time));
             reduced_y.push(tray.last().unwrap().value);
         });
         let trace = Scatter::new(reduced_x, reduced_y).name(key);
@@ -88,5 +311,48 @@ pub async fn scatter_chart_html(
         .x_axis(Axis::default().title("time").type_(AxisType::Date))
         .y_axis(y);
     plot.set_layout(layout);
-    plot.to_inline_html(div_id)
+    let mut html = String::new();
+    if !errors.is_empty() {
+        html.push_str("<ul>");
+        for e in errors {
+            html.push_str(&format!("<li>{}</li>", escape_html(&e)));
+        }
+        html.push_str("</ul>");
+    }
+    html.push_str(&plot.to_inline_html(div_id));
+    html
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chart_errors() {
+        let metrics = MetricQueues::new();
+        let mut syntheses = MetricSyntheses::new();
+        let cyclic = Expression::parse("b + 1", TimeUnit::Millis).unwrap();
+        syntheses.insert("b".into(), Box::new(cyclic));
+        let keys = ["b", "b * 2", "/(/"].into_iter();
+        let html = scatter_chart_html(&metrics, &syntheses, keys, .., TimeUnit::Millis, None, None);
+        let html = html.await;
+        assert!(html.contains("<li>`b`: cyclic syntheses `b` -&gt; `b`</li>"));
+        assert!(html.contains("<li>`b * 2`: cyclic syntheses"));
+        assert_eq!(html.matches("<li>").count(), 3);
+    }
 }
@@ -0,0 +1,471 @@
+//! Write-ahead log
+//!
+//! Every sample pushed to a [`crate::consumer::MetricConsumer`] and every deletion is appended to the log before it is applied.
+//! A checkpoint dumps all the queues as [`crate::codec::encode_checked_frames`] and truncates the log.
+//!
+//! The log and the checkpoint both carry a generation, which a checkpoint bumps.
+//! A log of a generation the checkpoint already covers is left over by a crash in between and is discarded.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    codec::{decode_sample, decode_time_unit, encode_key, encode_sample, encode_time_unit},
+    storage::sync_dir,
+    MetricKey, Sample, Time, TimeUnit, SAMPLE_SIZE,
+};
+
+const WAL_MAGIC: [u8; 4] = *b"MWAL";
+const WAL_VERSION: u8 = 3;
+/// Magic, version, time unit and generation
+const WAL_HEADER_SIZE: usize = 4 + 1 + 1 + 8;
+const CHECKPOINT_MAGIC: [u8; 4] = *b"MCKP";
+const CHECKPOINT_VERSION: u8 = 2;
+/// Magic, version and the generation of the log it covers
+const CHECKPOINT_HEADER_SIZE: usize = 4 + 1 + 8;
+const WAL_FILE: &str = "wal";
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+/// When appended records are forced to disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Every record survives a power loss once appended
+    #[default]
+    Always,
+    /// Once every this many records
+    Batch(usize),
+    /// On the first append this long after the last sync
+    ///
+    /// Nothing syncs a tail appended before the log goes idle but [`WriteAheadLog::sync`], so that is better called as often.
+    Interval(Duration),
+    /// Left to the OS; records still survive a process crash
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub enum WalRecord {
+    Sample(Sample),
+    /// Of the samples within the range, or of the whole queue if [`None`]
+    Deletion(Option<RangeInclusive<Time>>),
+}
+
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    dir: PathBuf,
+    file: File,
+    time_unit: TimeUnit,
+    fsync: FsyncPolicy,
+    /// Records appended since the last fsync
+    unsynced: usize,
+    last_sync: Instant,
+    len: u64,
+    generation: u64,
+    checkpoint_bytes: Option<u64>,
+    buf: Vec<u8>,
+}
+impl WriteAheadLog {
+    /// Records are timed in `time_unit`
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        time_unit: TimeUnit,
+        fsync: FsyncPolicy,
+    ) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(WAL_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut wal = Self {
+            dir,
+            file,
+            time_unit,
+            fsync,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            len: 0,
+            generation: 0,
+            checkpoint_bytes: None,
+            buf: vec![],
+        };
+        let checkpointed = wal.checkpoint_generation()?;
+        let header = fs::read(&path)?;
+        let header = header.get(..WAL_HEADER_SIZE);
+        match header {
+            Some(header) => {
+                let (unit, generation) = decode_wal_header(header)?;
+                if unit != time_unit {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "log is timed in another unit",
+                    ));
+                }
+                wal.generation = generation;
+                wal.len = wal.file.metadata()?.len();
+            }
+            None => wal.reset()?,
+        }
+        if let Some(checkpointed) = checkpointed.filter(|&c| wal.generation <= c) {
+            wal.generation = checkpointed + 1;
+            wal.reset()?;
+        }
+        Ok(wal)
+    }
+
+    pub fn fsync(&self) -> FsyncPolicy {
+        self.fsync
+    }
+    pub fn set_fsync(&mut self, fsync: FsyncPolicy) {
+        self.fsync = fsync;
+    }
+    /// The consumer takes a checkpoint before appending to a log grown past this many bytes
+    pub fn checkpoint_bytes(&self) -> Option<u64> {
+        self.checkpoint_bytes
+    }
+    pub fn set_checkpoint_bytes(&mut self, bytes: Option<u64>) {
+        self.checkpoint_bytes = bytes;
+    }
+    pub(crate) fn checkpoint_due(&self) -> bool {
+        self.checkpoint_bytes.is_some_and(|bytes| bytes < self.len)
+    }
+    /// Bumped by each checkpoint
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    /// Bytes of the log file
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len <= WAL_HEADER_SIZE as u64
+    }
+
+    pub fn append(&mut self, key: &str, record: &WalRecord) -> io::Result<()> {
+        // CRC, kind, key and payload
+        self.buf.clear();
+        self.buf.extend([0; 4]);
+        match record {
+            WalRecord::Sample(sample) => {
+                self.buf.push(0);
+                encode_key(&mut self.buf, key);
+                self.buf.extend(encode_sample(*sample));
+            }
+            WalRecord::Deletion(None) => {
+                self.buf.push(1);
+                encode_key(&mut self.buf, key);
+            }
+            WalRecord::Deletion(Some(range)) => {
+                self.buf.push(2);
+                encode_key(&mut self.buf, key);
+                self.buf.extend(range.start().to_be_bytes());
+                self.buf.extend(range.end().to_be_bytes());
+            }
+        }
+        let crc = crc32fast::hash(&self.buf[4..]);
+        self.buf[..4].copy_from_slice(&crc.to_be_bytes());
+        self.file.write_all(&self.buf)?;
+        self.len += self.buf.len() as u64;
+        self.unsynced += 1;
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Batch(n) => n <= self.unsynced,
+            FsyncPolicy::Interval(interval) => interval <= self.last_sync.elapsed(),
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced == 0 {
+            return Ok(());
+        }
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Drops a torn or corrupt tail left by a crash
+    pub fn records(&mut self) -> io::Result<Vec<(MetricKey, WalRecord)>> {
+        let data = fs::read(self.dir.join(WAL_FILE))?;
+        let mut rdr = data.get(WAL_HEADER_SIZE..).unwrap_or(&[]);
+        let mut records = vec![];
+        while let Some((record, rest)) = decode_record(rdr) {
+            records.push(record);
+            rdr = rest;
+        }
+        let valid = (data.len() - rdr.len()) as u64;
+        if valid < self.len {
+            self.file.set_len(valid)?;
+            self.file.sync_all()?;
+            self.len = valid;
+        }
+        Ok(records)
+    }
+
+    /// Atomically replaces the checkpoint with `frames` and empties the log
+    pub fn checkpoint(&mut self, frames: &[u8]) -> io::Result<()> {
+        let path = self.checkpoint_path();
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&encode_checkpoint_header(self.generation))?;
+        file.write_all(frames)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        sync_dir(&self.dir)?;
+        self.generation += 1;
+        self.reset()
+    }
+    /// The latest checkpoint, if any, as a header followed by frames
+    pub fn checkpoint_path(&self) -> PathBuf {
+        self.dir.join(CHECKPOINT_FILE)
+    }
+    /// Frames of the latest checkpoint, empty if there is none
+    pub fn checkpoint_frames(&self) -> io::Result<Vec<u8>> {
+        let mut data = match fs::read(self.checkpoint_path()) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        decode_checkpoint_header(&data)?;
+        data.drain(..CHECKPOINT_HEADER_SIZE);
+        Ok(data)
+    }
+    fn checkpoint_generation(&self) -> io::Result<Option<u64>> {
+        let mut header = [0; CHECKPOINT_HEADER_SIZE];
+        match File::open(self.checkpoint_path()) {
+            Ok(mut file) => {
+                io::Read::read_exact(&mut file, &mut header)?;
+                decode_checkpoint_header(&header).map(Some)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file
+            .write_all(&encode_wal_header(self.time_unit, self.generation))?;
+        self.file.sync_all()?;
+        self.len = WAL_HEADER_SIZE as u64;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+fn encode_wal_header(time_unit: TimeUnit, generation: u64) -> [u8; WAL_HEADER_SIZE] {
+    let mut buf = [0; WAL_HEADER_SIZE];
+    buf[..4].copy_from_slice(&WAL_MAGIC);
+    buf[4] = WAL_VERSION;
+    buf[5..6].copy_from_slice(&encode_time_unit(time_unit));
+    buf[6..].copy_from_slice(&generation.to_be_bytes());
+    buf
+}
+fn decode_wal_header(buf: &[u8]) -> io::Result<(TimeUnit, u64)> {
+    if buf[..4] != WAL_MAGIC || buf[4] != WAL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a write-ahead log",
+        ));
+    }
+    let generation = u64::from_be_bytes(buf[6..14].try_into().unwrap());
+    Ok((decode_time_unit([buf[5]])?, generation))
+}
+fn encode_checkpoint_header(generation: u64) -> [u8; CHECKPOINT_HEADER_SIZE] {
+    let mut buf = [0; CHECKPOINT_HEADER_SIZE];
+    buf[..4].copy_from_slice(&CHECKPOINT_MAGIC);
+    buf[4] = CHECKPOINT_VERSION;
+    buf[5..].copy_from_slice(&generation.to_be_bytes());
+    buf
+}
+fn decode_checkpoint_header(buf: &[u8]) -> io::Result<u64> {
+    let header = buf.get(..CHECKPOINT_HEADER_SIZE);
+    let Some(header) = header.filter(|h| h[..4] == CHECKPOINT_MAGIC && h[4] == CHECKPOINT_VERSION)
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a checkpoint",
+        ));
+    };
+    Ok(u64::from_be_bytes(header[5..].try_into().unwrap()))
+}
+
+/// [`None`] if the record is torn or corrupt
+fn decode_record(rdr: &[u8]) -> Option<((MetricKey, WalRecord), &[u8])> {
+    let crc = u32::from_be_bytes(rdr.get(..4)?.try_into().unwrap());
+    let body = &rdr[4..];
+    let kind = *body.first()?;
+    let key_len = usize::from(u16::from_be_bytes(body.get(1..3)?.try_into().unwrap()));
+    let payload_len = match kind {
+        0 => SAMPLE_SIZE,
+        1 => 0,
+        2 => 8 + 8,
+        _ => return None,
+    };
+    let body_len = 1 + 2 + key_len + payload_len;
+    let body = body.get(..body_len)?;
+    if crc32fast::hash(body) != crc {
+        return None;
+    }
+    let key = String::from_utf8(body[3..3 + key_len].to_vec()).ok()?;
+    let payload = &body[3 + key_len..];
+    let record = match kind {
+        0 => WalRecord::Sample(decode_sample(payload.try_into().unwrap())),
+        1 => WalRecord::Deletion(None),
+        _ => {
+            let start = Time::from_be_bytes(payload[..8].try_into().unwrap());
+            let end = Time::from_be_bytes(payload[8..].try_into().unwrap());
+            WalRecord::Deletion(Some(start..=end))
+        }
+    };
+    Some(((key, record), &rdr[4 + body_len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        consumer::{CardinalityLimits, MetricConsumer},
+        storage::DiskStore,
+    };
+
+    use super::*;
+
+    fn sample(time: Time) -> Sample {
+        Sample {
+            time,
+            value: time as f64,
+        }
+    }
+    fn open(dir: &Path) -> WriteAheadLog {
+        WriteAheadLog::open(dir, TimeUnit::Millis, FsyncPolicy::Always).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let dir = std::env::temp_dir().join(format!("metrics-wal-replay-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let key = "a".to_string();
+        let mut consumer = MetricConsumer::new(16);
+        consumer.set_wal(Some(open(&dir)));
+        for time in 0..3 {
+            consumer.push(&key)(sample(time)).unwrap();
+        }
+        consumer.delete_range(&key, 1..=1).unwrap();
+        // A crash right after the checkpoint is renamed into place leaves the log as it was
+        let log = fs::read(dir.join(WAL_FILE)).unwrap();
+        consumer.checkpoint().unwrap();
+        fs::write(dir.join(WAL_FILE), log).unwrap();
+
+        let mut consumer = MetricConsumer::new(16);
+        consumer.set_wal(Some(open(&dir)));
+        assert!(consumer.wal().unwrap().is_empty());
+        consumer.replay_wal().await.unwrap();
+        consumer.push(&key)(sample(3)).unwrap();
+        drop(consumer);
+
+        let mut consumer = MetricConsumer::new(16);
+        consumer.set_wal(Some(open(&dir)));
+        consumer.replay_wal().await.unwrap();
+        let span = consumer.metrics()[&key].span(..);
+        let times: Vec<Time> = span.iter().map(|sample| sample.time).collect();
+        assert_eq!(times, [0, 2, 3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_on_push() {
+        let dir = std::env::temp_dir().join(format!("metrics-wal-auto-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut wal = open(&dir);
+        wal.set_checkpoint_bytes(Some(WAL_HEADER_SIZE as u64));
+        let mut consumer = MetricConsumer::new(16);
+        consumer.set_wal(Some(wal));
+        let key = "a".to_string();
+        let mut push = consumer.push(&key);
+        push(sample(0)).unwrap();
+        push(sample(1)).unwrap();
+        drop(push);
+        assert_eq!(consumer.wal().unwrap().generation(), 1);
+        let frames = consumer.wal().unwrap().checkpoint_frames().unwrap();
+        assert!(!frames.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_checkpoint_frames() {
+        let dir = std::env::temp_dir().join(format!("metrics-wal-frames-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut consumer = MetricConsumer::new(16);
+        consumer.set_wal(Some(open(&dir)));
+        for key in ["a", "b", "c"] {
+            consumer.push(&key.into())(sample(0)).unwrap();
+        }
+        consumer.checkpoint().unwrap();
+        drop(consumer);
+
+        // Refused keys leave the frames after them readable
+        let mut consumer = MetricConsumer::new(16);
+        consumer.set_cardinality_limits(CardinalityLimits {
+            max_keys: Some(2),
+            ..Default::default()
+        });
+        consumer.set_wal(Some(open(&dir)));
+        consumer.replay_wal().await.unwrap();
+        assert_eq!(consumer.metrics().len(), 2);
+
+        let path = dir.join(CHECKPOINT_FILE);
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&path, data).unwrap();
+        let mut consumer = MetricConsumer::new(16);
+        consumer.set_wal(Some(open(&dir)));
+        let e = consumer.replay_wal().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_from_flush() {
+        let dir = std::env::temp_dir().join(format!("metrics-wal-flush-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let storage = || DiskStore::open(dir.join("store"), TimeUnit::Millis, Default::default());
+        let key = "a".to_string();
+        let mut consumer = MetricConsumer::new(16);
+        consumer.set_storage(Some(storage().unwrap()));
+        consumer.set_wal(Some(open(&dir)));
+        for time in 0..3 {
+            consumer.push(&key)(sample(time)).unwrap();
+        }
+        consumer.flush_storage().unwrap();
+        assert!(consumer.wal().unwrap().is_empty());
+        consumer.push(&key)(sample(3)).unwrap();
+        drop(consumer);
+
+        let mut consumer = MetricConsumer::new(16);
+        consumer.set_storage(Some(storage().unwrap()));
+        consumer.load_storage().unwrap();
+        consumer.set_wal(Some(open(&dir)));
+        consumer.replay_wal().await.unwrap();
+        let span = consumer.metrics()[&key].span(..);
+        let times: Vec<Time> = span.iter().map(|sample| sample.time).collect();
+        assert_eq!(times, [0, 1, 2, 3]);
+        consumer.flush_storage().unwrap();
+        let stored = consumer.storage().unwrap().series(&key).unwrap().read(..);
+        assert_eq!(stored.unwrap().len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,468 @@
+//! Windowed aggregations
+//!
+//! Each of them wraps another [`MetricSynthesis`], so they stack, e.g. a rolling max of a moving average of a [`Series`].
+
+use std::{collections::VecDeque, num::NonZeroU64};
+
+use primitive::ops::range::RangeAny;
+
+use crate::{
+    consumer::TimeSeriesSpan,
+    synthesis::{owned_span, read_sorted, Reach, SortedSamples},
+    view::{MetricSources, MetricSynthesis, SynthesisError},
+    MetricKey, Sample, Time,
+};
+
+/// A raw queue or another synthesis as a [`MetricSynthesis`] to be wrapped
+#[derive(Debug, Clone)]
+pub struct Series {
+    key: MetricKey,
+}
+impl Series {
+    pub fn new(key: MetricKey) -> Self {
+        Self { key }
+    }
+}
+impl MetricSynthesis for Series {
+    fn span<'a>(
+        &'a self,
+        sources: &MetricSources<'a>,
+        time_range: RangeAny<Time>,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        Ok(SortedSamples::from_sources(sources, &self.key, time_range)?.into_span())
+    }
+}
+
+/// NaN values are left out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    Mean,
+    Min,
+    Max,
+    StdDev,
+    /// Of `0.0..=100.0`, interpolated between the closest ranks
+    Percentile(f64),
+}
+
+/// [`Aggregation`] over the trailing `window` at each sample
+#[derive(Debug)]
+pub struct Rolling {
+    source: Box<dyn MetricSynthesis>,
+    window: Time,
+    aggregation: Aggregation,
+}
+impl Rolling {
+    pub fn new(
+        source: Box<dyn MetricSynthesis>,
+        window: NonZeroU64,
+        aggregation: Aggregation,
+    ) -> Self {
+        Self {
+            source,
+            window: window.get(),
+            aggregation,
+        }
+    }
+    /// Moving average
+    pub fn mean(source: Box<dyn MetricSynthesis>, window: NonZeroU64) -> Self {
+        Self::new(source, window, Aggregation::Mean)
+    }
+}
+impl MetricSynthesis for Rolling {
+    fn span<'a>(
+        &'a self,
+        sources: &MetricSources<'a>,
+        time_range: RangeAny<Time>,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        // Samples before the range are needed for the first windows within it
+        let reach = Reach::before(self.window);
+        let samples = source_samples(self.source.as_ref(), sources, &time_range, reach)?;
+        let mut samples = rolling(&samples, self.window, self.aggregation);
+        samples.retain(|sample| core::ops::RangeBounds::contains(&time_range, &sample.time));
+        Ok(owned_span(samples))
+    }
+}
+
+/// [`Aggregation`] over the trailing `window` at each of `samples` sorted by time
+///
+/// `window` must not be zero, or a sample would leave the window before it enters.
+pub(crate) fn rolling(samples: &[Sample], window: Time, aggregation: Aggregation) -> Vec<Sample> {
+    let mut state = Window::new(aggregation);
+    let mut start = 0;
+    let mut aggregated = Vec::with_capacity(samples.len());
+    for sample in samples {
+        state.push(sample.value);
+        // A window reaching past the last time keeps its samples
+        while samples[start]
+            .time
+            .checked_add(window)
+            .is_some_and(|end| end <= sample.time)
+        {
+            state.pop(samples[start].value);
+            start += 1;
+        }
+        aggregated.push(Sample {
+            time: sample.time,
+            value: state.value(),
+        });
+    }
+    aggregated
+}
+
+/// [`Aggregation`] over consecutive `step`-long buckets, timed at the bucket starts
+#[derive(Debug)]
+pub struct Tumbling {
+    source: Box<dyn MetricSynthesis>,
+    step: Time,
+    aggregation: Aggregation,
+}
+impl Tumbling {
+    pub fn new(
+        source: Box<dyn MetricSynthesis>,
+        step: NonZeroU64,
+        aggregation: Aggregation,
+    ) -> Self {
+        Self {
+            source,
+            step: step.get(),
+            aggregation,
+        }
+    }
+}
+impl MetricSynthesis for Tumbling {
+    fn span<'a>(
+        &'a self,
+        sources: &MetricSources<'a>,
+        time_range: RangeAny<Time>,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        // The last bucket within the range ends after it
+        let reach = Reach::after(self.step - 1);
+        let samples = source_samples(self.source.as_ref(), sources, &time_range, reach)?;
+        let mut aggregated = vec![];
+        for bucket in samples.chunk_by(|a, b| a.time / self.step == b.time / self.step) {
+            let time = bucket[0].time / self.step * self.step;
+            if !core::ops::RangeBounds::contains(&time_range, &time) {
+                continue;
+            }
+            let mut window = Window::new(self.aggregation);
+            bucket.iter().for_each(|sample| window.push(sample.value));
+            aggregated.push(Sample {
+                time,
+                value: window.value(),
+            });
+        }
+        Ok(owned_span(aggregated))
+    }
+}
+
+/// Exponentially weighted moving average
+///
+/// A sample weighs half as much after each `half_life`, so uneven sampling is accounted for.
+#[derive(Debug)]
+pub struct Ewma {
+    source: Box<dyn MetricSynthesis>,
+    half_life: Time,
+}
+impl Ewma {
+    pub fn new(source: Box<dyn MetricSynthesis>, half_life: NonZeroU64) -> Self {
+        Self {
+            source,
+            half_life: half_life.get(),
+        }
+    }
+}
+impl MetricSynthesis for Ewma {
+    fn span<'a>(
+        &'a self,
+        sources: &MetricSources<'a>,
+        time_range: RangeAny<Time>,
+    ) -> Result<TimeSeriesSpan<'a>, SynthesisError> {
+        // Every earlier sample still weighs in
+        let reach = Reach::before(Time::MAX);
+        let samples = source_samples(self.source.as_ref(), sources, &time_range, reach)?;
+        let mut average: Option<Sample> = None;
+        let mut aggregated = vec![];
+        for sample in samples {
+            let value = match average {
+                Some(prev) => {
+                    let half_lives = (sample.time - prev.time) as f64 / self.half_life as f64;
+                    let decay = 0.5_f64.powf(half_lives);
+                    prev.value * decay + sample.value * (1. - decay)
+                }
+                None => sample.value,
+            };
+            let sample = Sample {
+                time: sample.time,
+                value,
+            };
+            average = Some(sample);
+            if core::ops::RangeBounds::contains(&time_range, &sample.time) {
+                aggregated.push(sample);
+            }
+        }
+        Ok(owned_span(aggregated))
+    }
+}
+
+/// Values of a sliding window with the running state of an [`Aggregation`]
+struct Window {
+    aggregation: Aggregation,
+    count: usize,
+    mean: f64,
+    /// Sum of the squared differences from the mean, updated by Welford's method
+    m2: f64,
+    /// Candidates for the min or max in order of arrival
+    extremes: VecDeque<f64>,
+    /// All values for percentiles
+    sorted: Vec<f64>,
+}
+impl Window {
+    fn new(aggregation: Aggregation) -> Self {
+        Self {
+            aggregation,
+            count: 0,
+            mean: 0.,
+            m2: 0.,
+            extremes: VecDeque::new(),
+            sorted: vec![],
+        }
+    }
+
+    /// Whether `a` keeps `b` from being the extreme
+    fn dominates(&self, a: f64, b: f64) -> bool {
+        match self.aggregation {
+            Aggregation::Min => a < b,
+            _ => b < a,
+        }
+    }
+
+    fn push(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.count += 1;
+        match self.aggregation {
+            Aggregation::Mean | Aggregation::StdDev => {
+                let delta = value - self.mean;
+                self.mean += delta / self.count as f64;
+                self.m2 += delta * (value - self.mean);
+            }
+            Aggregation::Min | Aggregation::Max => {
+                while let Some(&last) = self.extremes.back() {
+                    if !self.dominates(value, last) {
+                        break;
+                    }
+                    self.extremes.pop_back();
+                }
+                self.extremes.push_back(value);
+            }
+            Aggregation::Percentile(_) => {
+                let pos = self.sorted.partition_point(|&x| x < value);
+                self.sorted.insert(pos, value);
+            }
+        }
+    }
+    /// `value` must be the oldest one in the window
+    fn pop(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.count -= 1;
+        match self.aggregation {
+            Aggregation::Mean | Aggregation::StdDev if self.count == 0 => {
+                self.mean = 0.;
+                self.m2 = 0.;
+            }
+            Aggregation::Mean | Aggregation::StdDev => {
+                let delta = value - self.mean;
+                self.mean -= delta / self.count as f64;
+                self.m2 -= delta * (value - self.mean);
+            }
+            Aggregation::Min | Aggregation::Max => {
+                if self.extremes.front() == Some(&value) {
+                    self.extremes.pop_front();
+                }
+            }
+            Aggregation::Percentile(_) => {
+                let pos = self.sorted.partition_point(|&x| x < value);
+                self.sorted.remove(pos);
+            }
+        }
+    }
+    fn value(&self) -> f64 {
+        let count = self.count as f64;
+        match self.aggregation {
+            _ if self.count == 0 => f64::NAN,
+            Aggregation::Mean => self.mean,
+            Aggregation::StdDev => (self.m2 / count).max(0.).sqrt(),
+            Aggregation::Min | Aggregation::Max => {
+                self.extremes.front().copied().unwrap_or(f64::NAN)
+            }
+            Aggregation::Percentile(percentile) => {
+                let Some(last) = self.sorted.len().checked_sub(1) else {
+                    return f64::NAN;
+                };
+                let rank = percentile.clamp(0., 100.) / 100. * last as f64;
+                let (low, high) = (rank.floor() as usize, rank.ceil() as usize);
+                let (low, high) = (self.sorted[low], self.sorted[high]);
+                low + (high - low) * rank.fract()
+            }
+        }
+    }
+}
+
+/// Samples of `source` within `time_range` widened by `reach` in order of time
+fn source_samples(
+    source: &dyn MetricSynthesis,
+    sources: &MetricSources<'_>,
+    time_range: &RangeAny<Time>,
+    reach: Reach,
+) -> Result<Vec<Sample>, SynthesisError> {
+    let span = |range| Ok(source.span(sources, range)?.samples);
+    read_sorted(span, reach.widen(time_range), reach.neighbors)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        consumer::{MetricQueue, MetricQueues},
+        view::MetricSyntheses,
+    };
+
+    use super::*;
+
+    fn samples(values: &[f64]) -> Vec<Sample> {
+        let samples = values.iter().enumerate();
+        let samples = samples.map(|(time, &value)| Sample {
+            time: time as Time * 10,
+            value,
+        });
+        samples.collect()
+    }
+    fn values(samples: &[Sample]) -> Vec<f64> {
+        samples.iter().map(|sample| sample.value).collect()
+    }
+
+    #[test]
+    fn test_rolling() {
+        let samples = samples(&[1., 5., 5., 2., 8., 3.]);
+        assert_eq!(
+            values(&rolling(&samples, 20, Aggregation::Mean)),
+            [1., 3., 5., 3.5, 5., 5.5]
+        );
+        assert_eq!(
+            values(&rolling(&samples, 30, Aggregation::Max)),
+            [1., 5., 5., 5., 8., 8.]
+        );
+        assert_eq!(
+            values(&rolling(&samples, 30, Aggregation::Min)),
+            [1., 1., 1., 2., 2., 2.]
+        );
+        assert_eq!(
+            values(&rolling(&samples, 30, Aggregation::Percentile(50.))),
+            [1., 3., 5., 5., 5., 3.]
+        );
+    }
+
+    #[test]
+    fn test_rolling_std_dev() {
+        // Large values with a small spread cancel out in the naive formula
+        let samples = samples(&[1e9 + 4., 1e9 + 7., 1e9 + 13., 1e9 + 16.]);
+        let std_devs = values(&rolling(&samples, 1000, Aggregation::StdDev));
+        assert_eq!(std_devs[0], 0.);
+        assert!((std_devs[3] - 22.5_f64.sqrt()).abs() < 1e-6);
+        let std_devs = values(&rolling(&samples, 20, Aggregation::StdDev));
+        assert!((std_devs[3] - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_rolling_nan() {
+        let samples = samples(&[1., f64::NAN, 3., f64::NAN, f64::NAN, f64::NAN]);
+        for (aggregation, expected) in [
+            (Aggregation::Mean, [1., 1., 2., 3., 3.]),
+            (Aggregation::Min, [1., 1., 1., 3., 3.]),
+            (Aggregation::Max, [1., 1., 3., 3., 3.]),
+            (Aggregation::Percentile(100.), [1., 1., 3., 3., 3.]),
+        ] {
+            let rolled = values(&rolling(&samples, 30, aggregation));
+            assert_eq!(rolled[..5], expected, "{aggregation:?}");
+            assert!(rolled[5].is_nan(), "{aggregation:?}");
+        }
+    }
+
+    fn queues(values: &[f64]) -> MetricQueues {
+        let mut queue = MetricQueue::new();
+        for &sample in &samples(values) {
+            queue.push(sample, 16).unwrap();
+        }
+        MetricQueues::from([("a".to_string(), Arc::new(queue))])
+    }
+    fn span(
+        synthesis: &dyn MetricSynthesis,
+        metrics: &MetricQueues,
+        time_range: impl core::ops::RangeBounds<Time>,
+    ) -> Vec<(Time, f64)> {
+        let syntheses = MetricSyntheses::new();
+        let sources = MetricSources::new(metrics, &syntheses);
+        let span = synthesis.span(&sources, RangeAny::from_range(time_range));
+        let samples = span.unwrap().samples;
+        samples.map(|sample| (sample.time, sample.value)).collect()
+    }
+
+    #[test]
+    fn test_tumbling() {
+        let metrics = queues(&[1., 5., 5., 2., 8., 3.]);
+        let step = NonZeroU64::new(20).unwrap();
+        let tumbling =
+            |aggregation| Tumbling::new(Box::new(Series::new("a".into())), step, aggregation);
+        assert_eq!(
+            span(&tumbling(Aggregation::Mean), &metrics, ..),
+            [(0, 3.), (20, 3.5), (40, 5.5)]
+        );
+        // Buckets are in the range by their starts
+        assert_eq!(
+            span(&tumbling(Aggregation::Max), &metrics, 15..),
+            [(20, 5.), (40, 8.)]
+        );
+        assert_eq!(
+            span(&tumbling(Aggregation::Mean), &metrics, ..=20),
+            [(0, 3.), (20, 3.5)]
+        );
+    }
+
+    #[test]
+    fn test_rolling_span() {
+        let metrics = queues(&[1., 5., 5., 2., 8., 3.]);
+        let rolling = Rolling::mean(
+            Box::new(Series::new("a".into())),
+            NonZeroU64::new(20).unwrap(),
+        );
+        // Samples before the range still fill the first windows
+        assert_eq!(span(&rolling, &metrics, 20..=30), [(20, 5.), (30, 3.5)]);
+        assert_eq!(span(&rolling, &metrics, 45..), [(50, 5.5)]);
+    }
+
+    #[test]
+    fn test_ewma() {
+        let metrics = queues(&[1., 5., 5., 2.]);
+        let ewma = Ewma::new(
+            Box::new(Series::new("a".into())),
+            NonZeroU64::new(10).unwrap(),
+        );
+        assert_eq!(
+            span(&ewma, &metrics, ..),
+            [(0, 1.), (10, 3.), (20, 4.), (30, 3.)]
+        );
+        // Samples before the range still weigh in
+        assert_eq!(span(&ewma, &metrics, 10..=20), [(10, 3.), (20, 4.)]);
+        let missing = Ewma::new(
+            Box::new(Series::new("b".into())),
+            NonZeroU64::new(10).unwrap(),
+        );
+        let syntheses = MetricSyntheses::new();
+        let sources = MetricSources::new(&metrics, &syntheses);
+        assert!(missing.span(&sources, RangeAny::from_range(..)).is_err());
+    }
+}